@@ -51,7 +51,7 @@ fn main() {
   println!("channels: {}", reader.spec().channels);
   println!("sample_rate: {}", reader.spec().sample_rate);
 
-  let samples = reader.samples::<i16>().map(|x| x.unwrap()).collect::<Vec<i16>>();
+  let samples = reader.samples::<i32>().map(|x| x.unwrap()).collect::<Vec<i32>>();
   let mut n = 0;
   for sample in samples {
     if n == 16 {
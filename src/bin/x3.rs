@@ -19,64 +19,538 @@
  *                                                                        *
  **************************************************************************/
 
+extern crate chrono;
 extern crate clap;
 extern crate x3;
 
-use clap::{App, Arg};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::process;
 
-#[derive(PartialEq, Eq)]
-enum AudioFiles {
-  X3a, // .x3a
-  Wav, // .wav
-}
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use x3::decodefile::{Concealment, DecodeOptions, DecodeReport, FrameAction, GapMode, StreamFormat, X3aReader};
+use x3::encodefile::EncodeOptions;
+use x3::error::X3Error;
+use x3::seekindex::{self, IndexLocation, SeekIndex};
+use x3::x3::{Filter, Parameters};
 
-fn get_filetype(filename: &str) -> AudioFiles {
-  if filename.ends_with(".x3a") {
-    return AudioFiles::X3a;
-  }
-  if filename.ends_with(".wav") {
-    return AudioFiles::Wav;
-  }
-  panic!("Invalid audio file, expecting a '.wav' or '.x3a' file: {}", filename);
-}
+// The exit codes, an archive that fails `verify` is not an error
+const EXIT_FAILED: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+// The file name of stdin and stdout
+const STDIO: &str = "-";
 
 fn main() {
   let matches = App::new("x3")
     .version("0.3.0")
     .author("Simon Werner <simonwerner@gmail.com>")
     .about("x3 - efficient lossless compression for low entropy audio wav files.")
-    .arg(
-      Arg::with_name("input")
-        .short("i")
-        .long("input")
-        .value_name("FILE")
-        .help("The input file, a .wav or .x3a file")
-        .required(true)
-        .takes_value(true),
+    .setting(AppSettings::SubcommandRequiredElseHelp)
+    .subcommand(
+      SubCommand::with_name("encode")
+        .about("Compress a .wav file to an .x3a archive")
+        .arg(input_arg("The .wav file, or - for stdin"))
+        .arg(output_arg("The .x3a file, or - for stdout"))
+        .arg(
+          Arg::with_name("block-len")
+            .long("block-len")
+            .value_name("N")
+            .help("The number of samples in a block, up to 60")
+            .default_value("20"),
+        )
+        .arg(
+          Arg::with_name("blocks-per-frame")
+            .long("blocks-per-frame")
+            .value_name("N")
+            .help("The number of blocks in a frame")
+            .default_value("500"),
+        )
+        .arg(
+          Arg::with_name("codes")
+            .long("codes")
+            .value_name("A,B,C")
+            .help("The three Rice codes, from 0 to 3")
+            .default_value("0,1,3"),
+        )
+        .arg(
+          Arg::with_name("thresholds")
+            .long("thresholds")
+            .value_name("A,B,C")
+            .help("The largest residual of a block that each Rice code is used for")
+            .default_value("3,8,20"),
+        )
+        .arg(
+          Arg::with_name("nbits")
+            .long("nbits")
+            .value_name("N")
+            .help("The number of bits used by the samples, from 8 up to the width of the wav samples")
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("filter")
+            .long("filter")
+            .value_name("FILTER")
+            .help("The predictor: a first, second or third order difference, or linear prediction")
+            .possible_values(&["diff", "diff2", "diff3", "lpc"])
+            .default_value("diff"),
+        )
+        .arg(
+          Arg::with_name("stereo")
+            .long("stereo")
+            .help("Code the left, right, mid or side channels of stereo files, whichever are smallest"),
+        )
+        .arg(
+          Arg::with_name("optimise")
+            .long("optimise")
+            .help("Search for the block length, Rice codes and thresholds that compress the input best"),
+        )
+        .arg(
+          Arg::with_name("exact-cost")
+            .long("exact-cost")
+            .help("Choose the coder of each block by its exact size, slower but smaller"),
+        )
+        .arg(
+          Arg::with_name("start-time")
+            .long("start-time")
+            .value_name("TIME")
+            .help("The time of the first sample as RFC 3339, e.g. 2020-06-01T12:00:00Z")
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("index")
+            .long("index")
+            .value_name("LOCATION")
+            .help("Write a seek index to a sidecar .x3i file or the end of the archive")
+            .possible_values(&["sidecar", "embedded"])
+            .takes_value(true),
+        ),
     )
-    .arg(
-      Arg::with_name("output")
-        .short("o")
-        .long("output")
-        .value_name("FILE")
-        .help("The output file, a .wav or .x3a file")
-        .required(true)
-        .takes_value(true),
+    .subcommand(
+      SubCommand::with_name("decode")
+        .about("Decompress an .x3a archive to a .wav file")
+        .arg(input_arg("The .x3a file, or - for stdin"))
+        .arg(output_arg("The .wav file, or - for stdout"))
+        .arg(
+          Arg::with_name("gaps")
+            .long("gaps")
+            .value_name("MODE")
+            .help("What to do with gaps between frames: report them, zero-fill them or split the output")
+            .possible_values(&["report", "zero-fill", "split"])
+            .default_value("report"),
+        )
+        .arg(
+          Arg::with_name("conceal")
+            .long("conceal")
+            .value_name("MODE")
+            .help("What to write for a damaged frame: drop it, zeros or hold the last sample")
+            .possible_values(&["drop", "zero", "hold"])
+            .default_value("drop"),
+        )
+        .arg(
+          Arg::with_name("best-effort")
+            .long("best-effort")
+            .help("Decode damaged frames up to the first invalid block before concealing them"),
+        )
+        .arg(
+          Arg::with_name("salvage")
+            .long("salvage")
+            .help("Decode the complete blocks of a last frame that is cut short"),
+        )
+        .arg(
+          Arg::with_name("raw")
+            .long("raw")
+            .help("Write the samples without a wav header, as signed little endian integers"),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("info")
+        .about("Show the archive header and statistics of an archive, without decoding it")
+        .arg(
+          Arg::with_name("input")
+            .value_name("FILE")
+            .help("The .x3a file")
+            .required(true),
+        )
+        .arg(
+          Arg::with_name("json")
+            .long("json")
+            .help("Print the information as JSON"),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("verify")
+        .about("Check the CRCs and decode every frame of archives, without writing any output")
+        .arg(
+          Arg::with_name("input")
+            .value_name("FILE")
+            .help("The .x3a files to check")
+            .required(true)
+            .multiple(true),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("repair")
+        .about("Rewrite an archive that ends part way through a frame as a valid archive")
+        .arg(input_arg("The truncated .x3a file"))
+        .arg(output_arg("The repaired .x3a file")),
     )
     .get_matches();
 
+  let result = match matches.subcommand() {
+    ("encode", Some(matches)) => encode(matches),
+    ("decode", Some(matches)) => decode(matches),
+    ("info", Some(matches)) => info(matches),
+    ("verify", Some(matches)) => verify(matches),
+    ("repair", Some(matches)) => repair(matches),
+    _ => unreachable!(),
+  };
+  match result {
+    Ok(code) => process::exit(code),
+    Err(message) => {
+      eprintln!("x3: {}", message);
+      process::exit(EXIT_ERROR);
+    }
+  }
+}
+
+fn input_arg(help: &str) -> Arg<'_, '_> {
+  Arg::with_name("input")
+    .short("i")
+    .long("input")
+    .value_name("FILE")
+    .help(help)
+    .required(true)
+    .takes_value(true)
+}
+
+fn output_arg(help: &str) -> Arg<'_, '_> {
+  Arg::with_name("output")
+    .short("o")
+    .long("output")
+    .value_name("FILE")
+    .help(help)
+    .required(true)
+    .takes_value(true)
+}
+
+fn encode(matches: &ArgMatches) -> Result<i32, String> {
   let in_file = matches.value_of("input").unwrap();
   let out_file = matches.value_of("output").unwrap();
 
-  let in_type = get_filetype(in_file);
-  let out_type = get_filetype(out_file);
+  let mut params = Parameters::new(
+    parse_arg(matches, "block-len")?,
+    parse_arg(matches, "blocks-per-frame")?,
+    parse_list(matches, "codes")?,
+    parse_list(matches, "thresholds")?,
+  )
+  .map_err(|err| format!("Invalid encoder parameters: {}", describe(&err)))?;
+  params.exact_cost = matches.is_present("exact-cost");
+  params.filter = Filter::from_name(&matches.value_of("filter").unwrap().to_uppercase()).unwrap();
+  params.decorrelate = matches.is_present("stereo");
+  let nbits = matches
+    .value_of("nbits")
+    .map(|_| parse_arg(matches, "nbits"))
+    .transpose()?;
 
-  if in_type == out_type {
-    panic!("Input must be different file type than output.");
+  let start_time = match matches.value_of("start-time") {
+    Some(t) => Some(
+      DateTime::parse_from_rfc3339(t)
+        .map_err(|err| format!("Invalid start time '{}': {}", t, err))?
+        .with_timezone(&Utc),
+    ),
+    None => None,
+  };
+  let options = EncodeOptions {
+    params,
+    nbits,
+    optimise: matches.is_present("optimise"),
+    start_time,
+    index: matches.value_of("index").map(|location| match location {
+      "embedded" => IndexLocation::Embedded,
+      _ => IndexLocation::Sidecar,
+    }),
+  };
+  let result = if in_file == STDIO || out_file == STDIO {
+    encode_stream(in_file, out_file, &options)
+  } else {
+    x3::encodefile::wav_to_x3a_with_options(in_file, out_file, &options)
+  };
+  result.map_err(|err| format!("Could not encode '{}': {}", in_file, describe(&err)))?;
+  Ok(0)
+}
+
+//
+// Encode when the input or output is stdin or stdout.  The index is written once the
+// archive is complete, so it needs an output file.
+//
+fn encode_stream(in_file: &str, out_file: &str, options: &EncodeOptions) -> Result<(), X3Error> {
+  if options.index.is_some() && out_file == STDIO {
+    return Err(io::Error::other("the seek index can't be written to stdout").into());
+  }
+
+  let input: Box<dyn Read> = match in_file {
+    STDIO => Box::new(io::stdin().lock()),
+    _ => Box::new(BufReader::new(File::open(in_file)?)),
+  };
+  let output: Box<dyn Write> = match out_file {
+    STDIO => Box::new(io::stdout().lock()),
+    _ => Box::new(File::create(out_file)?),
+  };
+  x3::encodefile::wav_to_x3a_stream(input, output, options)?;
+
+  match options.index {
+    Some(location) => seekindex::write_index(out_file, location, SeekIndex::DEFAULT_INTERVAL),
+    None => Ok(()),
+  }
+}
+
+fn decode(matches: &ArgMatches) -> Result<i32, String> {
+  let in_file = matches.value_of("input").unwrap();
+  let out_file = matches.value_of("output").unwrap();
+
+  let options = DecodeOptions {
+    gaps: match matches.value_of("gaps") {
+      Some("zero-fill") => GapMode::ZeroFill,
+      Some("split") => GapMode::Split,
+      _ => GapMode::Report,
+    },
+    concealment: match matches.value_of("conceal") {
+      Some("zero") => Concealment::Zero,
+      Some("hold") => Concealment::Hold,
+      _ => Concealment::Drop,
+    },
+    best_effort: matches.is_present("best-effort"),
+    salvage: matches.is_present("salvage"),
+  };
+  let raw = matches.is_present("raw");
+  if options.gaps == GapMode::Split && (raw || out_file == STDIO) {
+    return Err("--gaps split needs a wav output file".into());
   }
 
-  match in_type {
-    AudioFiles::Wav => x3::encodefile::wav_to_x3a(in_file, out_file).unwrap(),
-    AudioFiles::X3a => x3::decodefile::x3a_to_wav(in_file, out_file).unwrap(),
+  // The archive on stdin is decoded as it's read
+  let result = match in_file {
+    STDIO => X3aReader::new(io::stdin().lock()).and_then(|reader| decode_reader(reader, out_file, raw, &options)),
+    _ => X3aReader::open(in_file).and_then(|reader| decode_reader(reader, out_file, raw, &options)),
   };
+  let report = result.map_err(|err| format!("Could not decode '{}': {}", in_file, describe(&err)))?;
+  for gap in &report.gaps {
+    eprintln!(
+      "Gap of {} samples at {} us, expected {} us",
+      gap.samples, gap.time, gap.expected_time
+    );
+  }
+  for line in bad_frame_lines(&report) {
+    eprintln!("{}", line);
+  }
+  Ok(0)
+}
+
+//
+// Decode to a wav file, or to a wav or raw stream.
+//
+fn decode_reader<R: Read>(
+  x3a_reader: X3aReader<R>,
+  out_file: &str,
+  raw: bool,
+  options: &DecodeOptions,
+) -> Result<DecodeReport, X3Error> {
+  let format = if raw { StreamFormat::Raw } else { StreamFormat::Wav };
+  match out_file {
+    STDIO => x3a_reader.decode_to_writer(io::stdout().lock(), format, options),
+    _ if raw => x3a_reader.decode_to_writer(File::create(out_file)?, format, options),
+    _ => x3a_reader.decode_to_wav(out_file, options),
+  }
+}
+
+fn info(matches: &ArgMatches) -> Result<i32, String> {
+  let in_file = matches.value_of("input").unwrap();
+  let info =
+    x3::decodefile::x3a_info(in_file).map_err(|err| format!("Could not read '{}': {}", in_file, describe(&err)))?;
+  if matches.is_present("json") {
+    print_info_json(in_file, &info);
+  } else {
+    print_info(in_file, &info);
+  }
+  Ok(0)
+}
+
+fn verify(matches: &ArgMatches) -> Result<i32, String> {
+  let mut failed = 0;
+  for in_file in matches.values_of("input").unwrap() {
+    match x3::decodefile::verify_x3a(in_file) {
+      Ok(report) if report.is_ok() => {
+        println!("{}: OK, {} frames, {} samples", in_file, report.frames, report.samples)
+      }
+      Ok(report) => {
+        println!("{}: FAILED, {} bad frames", in_file, report.bad_frames.len());
+        for line in bad_frame_lines(&report) {
+          println!("{}", line);
+        }
+        failed += 1;
+      }
+      Err(err) => {
+        println!("{}: FAILED, {}", in_file, describe(&err));
+        failed += 1;
+      }
+    }
+  }
+  Ok(if failed > 0 { EXIT_FAILED } else { 0 })
+}
+
+fn repair(matches: &ArgMatches) -> Result<i32, String> {
+  let in_file = matches.value_of("input").unwrap();
+  let out_file = matches.value_of("output").unwrap();
+  let report = x3::decodefile::repair_x3a(in_file, out_file)
+    .map_err(|err| format!("Could not repair '{}': {}", in_file, describe(&err)))?;
+  match report.bad_frames.last().map(|bad_frame| &bad_frame.action) {
+    Some(FrameAction::Salvaged { decoded }) => println!("Salvaged {} samples of the last frame", decoded),
+    _ => println!("The archive is complete"),
+  }
+  Ok(0)
+}
+
+//
+// The value of a numeric argument.
+//
+fn parse_arg(matches: &ArgMatches, name: &str) -> Result<usize, String> {
+  let value = matches.value_of(name).unwrap();
+  value
+    .parse()
+    .map_err(|_| format!("Invalid --{} '{}', expected a number", name, value))
+}
+
+//
+// The value of an argument that is three numbers separated by commas.
+//
+fn parse_list(matches: &ArgMatches, name: &str) -> Result<[usize; 3], String> {
+  let value = matches.value_of(name).unwrap();
+  let error = || {
+    format!(
+      "Invalid --{} '{}', expected three numbers separated by commas",
+      name, value
+    )
+  };
+  let list = value
+    .split(',')
+    .map(|n| n.trim().parse::<usize>())
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|_| error())?;
+  list.try_into().map_err(|_| error())
+}
+
+//
+// A message for an error.
+//
+fn describe(err: &X3Error) -> String {
+  match err {
+    X3Error::Io(err) => err.to_string(),
+    X3Error::Hound(err) => err.to_string(),
+    err => format!("{:?}", err),
+  }
+}
+
+fn print_info(in_file: &str, info: &x3::decodefile::ArchiveInfo) {
+  println!("File: {}", in_file);
+  println!("Archive header:");
+  for element in &info.xml {
+    let mut line = format!("{:width$}{}", "", element.name, width = 2 + 2 * element.depth);
+    for (key, value) in &element.attributes {
+      line += &format!(" {}=\"{}\"", key, value);
+    }
+    if !element.text.is_empty() {
+      line += &format!(": {}", element.text);
+    }
+    println!("{}", line);
+  }
+  println!("Channels: {}", info.channels);
+  println!("Sample rate: {} Hz", info.sample_rate);
+  println!("Bits per sample: {}", info.nbits);
+  println!("Frames: {}", info.frames);
+  println!("Samples: {}", info.samples);
+  println!("Duration: {:.3} s", info.duration());
+  let time = |t| format_time(t).unwrap_or_else(|| "-".into());
+  println!("Start time: {}", time(info.start_time));
+  println!("End time: {}", time(info.end_time));
+  println!("Compression ratio: {:.3}", info.compression_ratio());
+  let source_ids: Vec<String> = info.source_ids.iter().map(|id| id.to_string()).collect();
+  println!("Source ids: {}", source_ids.join(", "));
+  println!("Seek index: {}", if info.index { "yes" } else { "no" });
+}
+
+fn print_info_json(in_file: &str, info: &x3::decodefile::ArchiveInfo) {
+  let xml: Vec<String> = info
+    .xml
+    .iter()
+    .map(|element| {
+      let attributes: Vec<String> = element
+        .attributes
+        .iter()
+        .map(|(key, value)| format!("{}: {}", json_string(key), json_string(value)))
+        .collect();
+      format!(
+        "{{\"name\": {}, \"depth\": {}, \"attributes\": {{{}}}, \"text\": {}}}",
+        json_string(&element.name),
+        element.depth,
+        attributes.join(", "),
+        json_string(&element.text)
+      )
+    })
+    .collect();
+  let time = |t| format_time(t).map_or_else(|| "null".into(), |t| json_string(&t));
+  let source_ids: Vec<String> = info.source_ids.iter().map(|id| id.to_string()).collect();
+
+  println!("{{");
+  println!("  \"file\": {},", json_string(in_file));
+  println!("  \"header\": [{}],", xml.join(", "));
+  println!("  \"channels\": {},", info.channels);
+  println!("  \"sample_rate\": {},", info.sample_rate);
+  println!("  \"bits_per_sample\": {},", info.nbits);
+  println!("  \"frames\": {},", info.frames);
+  println!("  \"samples\": {},", info.samples);
+  println!("  \"duration\": {},", info.duration());
+  println!("  \"start_time\": {},", time(info.start_time));
+  println!("  \"end_time\": {},", time(info.end_time));
+  println!("  \"compression_ratio\": {},", info.compression_ratio());
+  println!("  \"source_ids\": [{}],", source_ids.join(", "));
+  println!("  \"seek_index\": {}", info.index);
+  println!("}}");
+}
+
+//
+// A time in microseconds since the Unix epoch as RFC 3339.
+//
+fn format_time(time: Option<i64>) -> Option<String> {
+  let time = DateTime::from_timestamp_micros(time?)?;
+  Some(time.to_rfc3339_opts(SecondsFormat::Micros, true))
+}
+
+//
+// A JSON string, with the quotes.
+//
+fn json_string(s: &str) -> String {
+  let mut json = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => json.push_str("\\\""),
+      '\\' => json.push_str("\\\\"),
+      c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+      c => json.push(c),
+    }
+  }
+  json.push('"');
+  json
+}
+
+//
+// A line for each damaged frame of a report.
+//
+fn bad_frame_lines(report: &x3::decodefile::DecodeReport) -> impl Iterator<Item = String> + '_ {
+  report.bad_frames.iter().map(|bad_frame| {
+    format!(
+      "Frame {} at byte {} (sample {}): {:?}, {:?}",
+      bad_frame.frame, bad_frame.offset, bad_frame.sample, bad_frame.error, bad_frame.action
+    )
+  })
 }
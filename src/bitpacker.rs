@@ -72,6 +72,7 @@ impl<'a, W: ByteWriter> BitPacker<'a, W> {
     }
   }
 
+  #[allow(clippy::needless_return)]
   pub fn crc(&self) -> u16 {
     return self.crc;
   }
@@ -85,6 +86,7 @@ impl<'a, W: ByteWriter> BitPacker<'a, W> {
     Ok(())
   }
 
+  #[allow(clippy::needless_return, clippy::len_without_is_empty)]
   pub fn len(&self) -> usize {
     return self.byte_len;
   }
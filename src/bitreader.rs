@@ -59,6 +59,9 @@ pub struct BitReader<'a> {
 
   /// The remaining number of bits to process in the word
   rem_bit: usize,
+
+  /// The number of bits read, this can be past the end of the array
+  bits_read: usize,
 }
 
 impl<'a> BitReader<'a> {
@@ -69,14 +72,24 @@ impl<'a> BitReader<'a> {
       idx,
       leading_word,
       rem_bit: idx * 8,
+      bits_read: 0,
     }
   }
 
+  ///
+  /// The number of bits read so far.  The bits past the end of the array are read as zeros,
+  /// so a reader that has gone past the end has a value larger than `8 * array.len()`.
+  ///
+  pub fn bits_read(&self) -> usize {
+    self.bits_read
+  }
+
   /// Increment the bits, load a new byte if required.
   #[inline(always)]
   pub fn inc_bits(&mut self, n: usize) {
     debug_assert!(n < BIT_LEN);
 
+    self.bits_read += n;
     if n < self.rem_bit {
       self.leading_word <<= n;
       self.rem_bit -= n;
@@ -302,4 +315,19 @@ mod tests {
       assert_eq!(0, br.leading_word);
     }
   }
+
+  #[test]
+  fn test_bitreader_bits_read() {
+    let inp_arr: &mut [u8] = &mut [0xff, 0x00, 0x0f];
+    let mut br = BitReader::new(inp_arr);
+
+    assert_eq!(0xff, br.read_nbits(8));
+    assert_eq!(12, br.count_zero_bits());
+    assert_eq!(0x0f, br.read_nbits(4));
+    assert_eq!(24, br.bits_read());
+
+    // Past the end of the array
+    assert_eq!(0, br.read_nbits(3));
+    assert_eq!(27, br.bits_read());
+  }
 }
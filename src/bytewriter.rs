@@ -1,4 +1,5 @@
 use crate::error::{Result, X3Error};
+use crate::x3::{Frame, FrameHeader};
 
 #[cfg(not(feature="std"))]
 pub enum SeekFrom{
@@ -19,6 +20,14 @@ pub trait ByteWriter {
     // seeking
     fn seek(&mut self, pos: SeekFrom)-> Result<u64>;
     fn stream_position(&mut self)-> Result<u64>;
+
+    ///
+    /// Called at the end of each frame, the bytes before this are not written again.  A
+    /// writer that stages frames writes them out, others do nothing.
+    ///
+    fn frame_end(&mut self)-> Result<()> {
+        Ok(())
+    }
 }
 
 ///
@@ -79,6 +88,7 @@ impl<'a> ByteWriter for SliceByteWriter<'a> {
         Ok(self.p_byte as u64)
     }
 
+    #[allow(clippy::needless_return)]
     fn stream_position(&mut self)-> Result<u64> {
         return Ok(self.p_byte as u64);    
     }
@@ -99,6 +109,220 @@ impl<'a> ByteWriter for SliceByteWriter<'a> {
     }
 }
 
+///
+/// The buffer length that a FrameByteWriter needs to hold the longest frame, with the byte
+/// that aligns it to a word.
+///
+pub const FRAME_BUFFER_LENGTH: usize = 1 + FrameHeader::LENGTH + Frame::MAX_LENGTH;
+
+///
+/// Generic trait for the destination of a FrameByteWriter, the bytes are written in order
+/// and never sought back to.  With std it's implemented for every io::Write.
+///
+pub trait ByteSink {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<()>;
+    fn flush(&mut self) -> Result<()>;
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> ByteSink for W {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+        std::io::Write::write_all(self, bytes).map_err(X3Error::from)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        std::io::Write::flush(self).map_err(X3Error::from)
+    }
+}
+
+///
+/// Wrapper struct implementing ByteWriter trait for a sink that can't seek (e.g. a pipe,
+/// a socket or a compressor).  Each frame is staged in a caller provided buffer, where the
+/// frame header is written after the payload, then it's written to the sink when the
+/// encoder calls `frame_end`.  The buffer must hold the longest frame, FRAME_BUFFER_LENGTH
+/// bytes.
+///
+pub struct FrameByteWriter<'a, S: ByteSink> {
+    sink: S,
+    buffer: &'a mut [u8],
+    flushed: u64,
+    p_byte: usize,
+    stream_length: usize,
+}
+
+impl<'a, S: ByteSink> FrameByteWriter<'a, S> {
+    pub fn new(sink: S, buffer: &'a mut [u8]) -> Self {
+        FrameByteWriter {
+            sink,
+            buffer,
+            flushed: 0,
+            p_byte: 0,
+            stream_length: 0,
+        }
+    }
+
+    ///
+    /// The sink, the bytes staged since the last flush are not written.
+    ///
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl<'a, S: ByteSink> ByteWriter for FrameByteWriter<'a, S> {
+    fn align<const N: usize>(&mut self) -> Result<usize> {
+        let residual = (self.flushed as usize + self.p_byte) % N;
+        if residual == 0 {
+            return Ok(0);
+        }
+        let zero_array = [0u8; N];
+        self.write_all(&zero_array[residual..])?;
+        Ok(N - residual)
+    }
+
+    ///
+    /// Write the staged bytes to the sink, the next bytes are written after them.
+    ///
+    fn flush(&mut self) -> Result<()> {
+        self.sink.write_all(&self.buffer[..self.stream_length])?;
+        self.sink.flush()?;
+        self.flushed += self.stream_length as u64;
+        self.p_byte = 0;
+        self.stream_length = 0;
+        Ok(())
+    }
+
+    fn frame_end(&mut self) -> Result<()> {
+        self.flush()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let abs_pos = match pos {
+            SeekFrom::Current(pos) => (self.flushed + self.p_byte as u64) as i64 + pos,
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(pos) => (self.flushed + self.stream_length as u64) as i64 + pos,
+        };
+        if abs_pos < self.flushed as i64 {
+            return Err(X3Error::ByteWriterSeekFlushed);
+        }
+        let p_byte = (abs_pos as u64 - self.flushed) as usize;
+        if p_byte > self.buffer.len() {
+            return Err(X3Error::ByteWriterInsufficientMemory);
+        }
+
+        // The buffer holds the last frame, skipped bytes are zero
+        if p_byte > self.stream_length {
+            self.buffer[self.stream_length..p_byte].fill(0);
+            self.stream_length = p_byte;
+        }
+        self.p_byte = p_byte;
+        Ok(abs_pos as u64)
+    }
+
+    fn stream_position(&mut self) -> Result<u64> {
+        Ok(self.flushed + self.p_byte as u64)
+    }
+
+    fn write_all(&mut self, value: impl AsRef<[u8]>) -> Result<()> {
+        let value = value.as_ref();
+        if value.len() > self.buffer[self.p_byte..].len() {
+            return Err(X3Error::ByteWriterInsufficientMemory);
+        }
+        self.buffer[self.p_byte..self.p_byte + value.len()].copy_from_slice(value);
+
+        self.p_byte += value.len();
+        if self.p_byte > self.stream_length {
+            self.stream_length = self.p_byte;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub use vec_byte_writer::*;
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub mod vec_byte_writer {
+    use alloc::vec::Vec;
+    use crate::bytewriter::{ByteWriter, SeekFrom};
+    use crate::error::{Result, X3Error};
+
+    ///
+    /// Wrapper struct implementing ByteWriter trait to a Vec, which grows as it's written to
+    ///
+    #[derive(Default)]
+    pub struct VecByteWriter {
+        vec: Vec<u8>,
+        p_byte: usize,
+    }
+
+    impl VecByteWriter {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        ///
+        /// The bytes that have been written
+        ///
+        pub fn as_bytes(&self) -> &[u8] {
+            &self.vec
+        }
+
+        pub fn into_inner(self) -> Vec<u8> {
+            self.vec
+        }
+    }
+
+    impl ByteWriter for VecByteWriter {
+        fn align<const N: usize>(&mut self) -> Result<usize> {
+            let residual = self.p_byte % N;
+            if residual == 0 {
+                return Ok(0);
+            }
+            let zero_array = [0u8; N];
+            self.write_all(&zero_array[residual..])?;
+            Ok(N - residual)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+            let abs_pos = match pos {
+                SeekFrom::Current(pos) => (self.p_byte as i64) + pos,
+                SeekFrom::Start(pos) => pos as i64,
+                SeekFrom::End(pos) => self.vec.len() as i64 + pos,
+            };
+            if abs_pos < 0 {
+                return Err(X3Error::ByteWriterSeekBeforeStart);
+            }
+            let abs_pos = abs_pos as usize;
+            if abs_pos > self.vec.len() {
+                self.vec.resize(abs_pos, 0);
+            }
+            self.p_byte = abs_pos;
+            Ok(self.p_byte as u64)
+        }
+
+        fn stream_position(&mut self) -> Result<u64> {
+            Ok(self.p_byte as u64)
+        }
+
+        fn write_all(&mut self, value: impl AsRef<[u8]>) -> Result<()> {
+            let value = value.as_ref();
+            let end = self.p_byte + value.len();
+            if end > self.vec.len() {
+                self.vec.resize(end, 0);
+            }
+            self.vec[self.p_byte..end].copy_from_slice(value);
+            self.p_byte = end;
+
+            Ok(())
+        }
+    }
+}
 
 #[cfg(feature = "std")]
 pub use stream_byte_writer::*;
@@ -143,6 +367,7 @@ pub mod stream_byte_writer{
             Ok( N - residual)
         }
 
+        #[allow(clippy::redundant_closure)]
         fn flush(&mut self)-> crate::error::Result<()> {
             self.writer.flush().map_err(|e| X3Error::Io(e))
         }
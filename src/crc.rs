@@ -41,6 +41,7 @@ const CRC_TABLE: [u16; 256] = [
   0x2e93, 0x3eb2, 0x0ed1, 0x1ef0,
 ];
 
+#[allow(clippy::needless_return)]
 pub fn update_crc16(crc: u16, data: &u8) -> u16 {
   let lookup: usize = (data ^ (crc >> 8) as u8) as usize;
   return (crc << 8) ^ CRC_TABLE[lookup];
@@ -20,81 +20,593 @@
  **************************************************************************/
 
 // std
+use std::format;
 use std::fs::File;
-use std::io::{prelude::*, BufReader};
+use std::io::{prelude::*, BufReader, BufWriter, ErrorKind, SeekFrom};
 use std::path;
-use std::println;
 use std::string::String;
 use std::vec;
 use std::vec::Vec;
 
 // externs
+use crate::byteorder::{ByteOrder, LittleEndian};
 use crate::hound;
 
 // this crate
+use crate::bytereader::ByteReader;
+use crate::bytewriter::StreamByteWriter;
 use crate::decoder;
+use crate::encoder;
 use crate::error;
+use crate::seekindex::{self, SeekIndex, SeekPoint};
 use crate::{crc, x3};
 
-use crate::x3::{FrameHeader, X3aSpec};
+use crate::x3::{FrameHeader, Sample, X3aSpec};
 use error::X3Error;
 use quick_xml::events::Event;
 use quick_xml::Reader;
 
-pub const X3_READ_BUFFER_SIZE: usize = 1024 * 24;
+pub const X3_READ_BUFFER_SIZE: usize = x3::Frame::MAX_LENGTH;
 pub const X3_WRITE_BUFFER_SIZE: usize = X3_READ_BUFFER_SIZE * 8;
 
-pub struct X3aReader {
-  reader: BufReader<File>,
+///
+/// A break in the timeline of an archive, found by comparing the time of a frame with the
+/// time expected from the previous frame.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Gap {
+  /// The time the frame should have started at, microseconds since the Unix epoch.
+  pub expected_time: i64,
+
+  /// The time of the frame, microseconds since the Unix epoch.
+  pub time: i64,
+
+  /// The number of samples of each channel that are missing, this is negative when the
+  /// frame overlaps the previous frame.
+  pub samples: i64,
+}
+
+///
+/// How a damaged frame was handled.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameAction {
+  /// The frame header or payload is damaged, decoding continued from the next good frame
+  /// header.  `skipped_bytes` were passed over, including the damaged frame.
+  Resync { skipped_bytes: usize },
+
+  /// The frame is intact but could not be decoded, it was left out.
+  Skipped,
+
+  /// The samples of the frame were replaced as `Concealment` says.  The first `decoded`
+  /// samples of each channel were decoded from the damaged payload, in best effort mode.
+  Concealed { decoded: usize },
+
+  /// The archive ends part way through the frame, the first `decoded` samples of each
+  /// channel were in complete blocks and were kept.
+  Salvaged { decoded: usize },
+
+  /// The archive ends part way through the frame, it was left out.  Salvage keeps its
+  /// complete blocks.
+  Dropped,
+}
+
+///
+/// What is written in place of a frame that has an intact header but can't be decoded,
+/// such as one that fails its payload CRC.  Concealing the frame keeps the samples after it
+/// at the right time.  When the header is damaged the number of samples is not known, the
+/// frame is dropped and reported as a gap if the frames have a time.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Concealment {
+  /// Leave the frame out.
+  #[default]
+  Drop,
+
+  /// Write zeros.
+  Zero,
+
+  /// Repeat the last sample of each channel.
+  Hold,
+}
+
+///
+/// A frame that could not be decoded.
+///
+#[derive(Debug)]
+pub struct BadFrame {
+  /// The position of the frame header in the archive.
+  pub offset: u64,
+
+  /// The number of the frame, counted from the start of the archive.
+  pub frame: u64,
+
+  /// The first sample of the frame, counted for each channel from the start of the archive.
+  pub sample: u64,
+
+  /// Why the frame could not be decoded.
+  pub error: X3Error,
+
+  /// What was done with it.
+  pub action: FrameAction,
+}
+
+///
+/// The result of decoding an archive.  The frames lost when a header is damaged are not
+/// known, so the frame and sample counts after a resync only count the frames that were read.
+///
+#[derive(Debug, Default)]
+pub struct DecodeReport {
+  /// The number of frames decoded.
+  pub frames: u64,
+
+  /// The number of samples of each channel written, concealed samples included.
+  pub samples: u64,
+
+  /// Every frame that could not be decoded, in the order they were found.
+  pub bad_frames: Vec<BadFrame>,
+
+  /// Every gap in the timeline, in the order they were found.  These are not damage, the
+  /// recorder may have been paused.
+  pub gaps: Vec<Gap>,
+}
+
+impl DecodeReport {
+  ///
+  /// There were no damaged frames.
+  ///
+  pub fn is_ok(&self) -> bool {
+    self.bad_frames.is_empty()
+  }
+}
+
+///
+/// What `x3a_to_wav_with_options` does when there is a gap between frames.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GapMode {
+  /// Print the gap, the frames are written one after the other.
+  #[default]
+  Report,
+
+  /// Write zeros for the missing samples.  Overlaps are only reported.
+  ZeroFill,
+
+  /// Start a new wav file after each gap or overlap, "out.wav" is followed by "out_1.wav",
+  /// "out_2.wav" and so on.
+  Split,
+}
+
+///
+/// Options for `x3a_to_wav_with_options`.
+///
+#[derive(Clone, Default)]
+pub struct DecodeOptions {
+  /// How gaps between frames are handled.
+  pub gaps: GapMode,
+
+  /// What is written in place of damaged frames.
+  pub concealment: Concealment,
+
+  /// Decode a damaged frame up to the first invalid block, then conceal the rest.  This has
+  /// no effect with `Concealment::Drop`.
+  pub best_effort: bool,
+
+  /// Keep the complete blocks of a frame that is cut short by the end of the archive.
+  pub salvage: bool,
+}
+
+///
+/// How `x3a_to_writer` writes the samples.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StreamFormat {
+  /// A wav header, then the samples.  The length is not known when the header is written,
+  /// so the header gives the longest length possible.
+  #[default]
+  Wav,
+
+  /// Only the interleaved samples, little endian and signed.  The samples are 1, 2, 3 or 4
+  /// bytes, the number of bits rounded up to whole bytes.
+  Raw,
+}
+
+///
+/// An element of the XML archive header.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct XmlElement {
+  /// The number of elements this element is inside of.
+  pub depth: usize,
+
+  pub name: String,
+  pub attributes: Vec<(String, String)>,
+
+  /// The text of the element, this is empty when it only holds other elements.
+  pub text: String,
+}
+
+///
+/// What an archive holds, this is read from the archive header and the frame headers without
+/// decoding any frames.
+///
+#[derive(Clone, Debug, Default)]
+pub struct ArchiveInfo {
+  /// The XML archive header, in the order of the elements.
+  pub xml: Vec<XmlElement>,
+
+  pub sample_rate: u32,
+  pub channels: u8,
+  pub nbits: usize,
+
+  /// The number of audio frames, and the samples of each channel in them.
+  pub frames: u64,
+  pub samples: u64,
+
+  /// The time of the first sample and the end of the last frame, microseconds since the Unix
+  /// epoch.  These are `None` when the frames don't have a time.
+  pub start_time: Option<i64>,
+  pub end_time: Option<i64>,
+
+  /// The source ids of the audio frames.
+  pub source_ids: Vec<u8>,
+
+  /// The archive has a seek index.
+  pub index: bool,
+
+  /// The length of the archive file.
+  pub file_len: u64,
+}
+
+impl ArchiveInfo {
+  ///
+  /// The length of the recording in seconds.
+  ///
+  pub fn duration(&self) -> f64 {
+    self.samples as f64 / self.sample_rate.max(1) as f64
+  }
+
+  ///
+  /// The size of the audio as wav samples, divided by the size of the archive.
+  ///
+  pub fn compression_ratio(&self) -> f64 {
+    let wav_len = self.samples * self.channels as u64 * self.nbits.div_ceil(8) as u64;
+    wav_len as f64 / self.file_len.max(1) as f64
+  }
+}
+
+pub struct X3aReader<R: Read = BufReader<File>> {
+  reader: R,
   spec: X3aSpec,
-  remaing_bytes: usize,
-  read_buf: [u8; X3_READ_BUFFER_SIZE],
 
-  /// The count of errors.
-  /// TODO: Count each type of error
-  frame_errors: usize,
+  /// The position of the next byte to read, counted from the start of the archive.
+  pos: usize,
+
+  /// Bytes that were read past while looking for a frame header, these are read again
+  /// before the reader.
+  pending: Vec<u8>,
+
+  /// The XML archive header.
+  xml: String,
+
+  /// The position of the first frame.
+  data_start: usize,
+
+  /// The seek index, read from a sidecar file or the end of the archive.
+  index: Option<SeekIndex>,
+
+  /// The number of samples of each channel to drop from the start of the next frame, after
+  /// a seek.
+  skip_samples: usize,
+
+  /// The frame header, then the payload.
+  read_buf: [u8; FrameHeader::LENGTH + X3_READ_BUFFER_SIZE],
+  planar_buf: Vec<i32>,
+
+  /// The time the next frame should start, if the frames have a time.
+  next_time: Option<i64>,
+
+  /// The gap before the last frame.
+  gap: Option<Gap>,
+
+  /// The number and first sample of the next frame.
+  frame: u64,
+  sample: u64,
+
+  /// The frames decoded so far, and those that couldn't be.
+  report: DecodeReport,
+
+  /// How damaged frames are concealed, and the last sample of each channel to hold.
+  concealment: Concealment,
+  best_effort: bool,
+  last_samples: Vec<i32>,
+
+  /// Decode the last frame when the archive ends part way through it.
+  salvage: bool,
 }
 
 impl X3aReader {
+  ///
+  /// Open an archive.  The seek index is used when there is a sidecar ".x3i" file or an index
+  /// at the end of the archive, otherwise seeking reads the frame headers.
+  ///
   pub fn open<P: AsRef<path::Path>>(filename: P) -> Result<Self, X3Error> {
-    let file = File::open(filename).unwrap();
-    let mut remaing_bytes = file.metadata()?.len() as usize;
-    let mut reader = BufReader::with_capacity(64 * 1024, file);
+    let mut file = File::open(&filename)?;
+    let index = seekindex::read_index(&filename, &mut file).ok();
+    file.rewind()?;
 
-    let (spec, header_size) = read_archive_header(&mut reader)?;
-    remaing_bytes -= header_size;
+    let mut x3a_reader = Self::new(BufReader::with_capacity(64 * 1024, file))?;
+    x3a_reader.index = index;
+    Ok(x3a_reader)
+  }
+}
 
-    Ok(Self {
+impl<R: Read> X3aReader<R> {
+  ///
+  /// Read an archive from `reader`, such as a buffer in memory or a network stream, which is
+  /// at the start of the archive.  The frames are read in order and the archive ends when
+  /// `reader` does, seeking needs a reader that can seek.
+  ///
+  pub fn new(mut reader: R) -> Result<Self, X3Error> {
+    let (spec, xml, data_start) = read_archive_header(&mut reader)?;
+    let mut x3a_reader = Self {
       reader,
       spec,
-      remaing_bytes,
-      read_buf: [0u8; X3_READ_BUFFER_SIZE],
-      frame_errors: 0,
-    })
+      pos: data_start,
+      pending: Vec::new(),
+      xml,
+      data_start,
+      index: None,
+      skip_samples: 0,
+      read_buf: [0u8; FrameHeader::LENGTH + X3_READ_BUFFER_SIZE],
+      planar_buf: vec![0i32; X3_WRITE_BUFFER_SIZE],
+      next_time: None,
+      gap: None,
+      frame: 0,
+      sample: 0,
+      report: DecodeReport::default(),
+      concealment: Concealment::Drop,
+      best_effort: false,
+      last_samples: Vec::new(),
+      salvage: false,
+    };
+
+    // The number of channels is taken from the first frame, the archive header does not
+    // always have it.  The frame header is read again when the frame is decoded.
+    let len = x3a_reader.read_bytes(0, FrameHeader::LENGTH)?;
+    if let Ok(header) = decoder::read_frame_header(&x3a_reader.read_buf[..len]) {
+      if header.channels == 0 {
+        return Err(X3Error::InvalidNumChannels);
+      }
+      x3a_reader.spec.channels = header.channels;
+    }
+    x3a_reader.pending.extend_from_slice(&x3a_reader.read_buf[..len]);
+    x3a_reader.pos -= len;
+
+    x3a_reader.last_samples = vec![0; x3a_reader.spec.channels as usize];
+    Ok(x3a_reader)
   }
 
   pub fn spec(&self) -> &X3aSpec {
     &self.spec
   }
 
-  fn read_bytes(&mut self, mut buf_len: usize) -> std::io::Result<()> {
-    if self.remaing_bytes < buf_len {
-      buf_len = self.remaing_bytes;
+  ///
+  /// The XML archive header.
+  ///
+  pub fn xml(&self) -> &str {
+    &self.xml
+  }
+
+  ///
+  /// The gap or overlap between the previous frame and the last frame that was read, `None`
+  /// when they are contiguous.  Frames with a zero time have no time, and are not checked.
+  ///
+  pub fn gap(&self) -> Option<Gap> {
+    self.gap
+  }
+
+  ///
+  /// Set what is written in place of damaged frames, they are dropped by default.  With
+  /// `best_effort` a damaged frame is decoded up to the first invalid block.
+  ///
+  pub fn set_concealment(&mut self, concealment: Concealment, best_effort: bool) {
+    self.concealment = concealment;
+    self.best_effort = best_effort;
+  }
+
+  ///
+  /// Decode the complete blocks of the last frame when the archive ends part way through it,
+  /// such as when a recorder loses power.  Otherwise the frame is ignored.
+  ///
+  pub fn set_salvage(&mut self, salvage: bool) {
+    self.salvage = salvage;
+  }
+
+  ///
+  /// The frames decoded so far, and every frame that could not be decoded.
+  ///
+  pub fn report(&self) -> &DecodeReport {
+    &self.report
+  }
+
+  ///
+  /// The seek index of the archive, if it has one.
+  ///
+  pub fn index(&self) -> Option<&SeekIndex> {
+    self.index.as_ref()
+  }
+
+  ///
+  /// Use `index` to seek, such as one read with `SeekIndex::read` from the end of the archive.
+  ///
+  pub fn set_index(&mut self, index: SeekIndex) {
+    self.index = Some(index);
+  }
+
+  //
+  // Compare the time of the frame with the end of the previous frame.  A difference of less
+  // than half a sample is rounding.  A gap is added to the report.
+  //
+  fn check_time(&mut self, header: &FrameHeader) -> Option<Gap> {
+    let sample_rate = self.spec.sample_rate as i64;
+    if header.time == 0 || sample_rate == 0 {
+      self.next_time = None;
+      return None;
     }
-    self.remaing_bytes -= buf_len;
-    self.reader.read_exact(&mut self.read_buf[0..buf_len])
+
+    let expected_time = self.next_time;
+    self.next_time = Some(header.time + header.samples as i64 * 1_000_000 / sample_rate);
+
+    let expected_time = expected_time?;
+    let samples = ((header.time - expected_time) as f64 * sample_rate as f64 / 1e6).round() as i64;
+    if samples == 0 {
+      return None;
+    }
+    let gap = Gap {
+      expected_time,
+      time: header.time,
+      samples,
+    };
+    self.report.gaps.push(gap);
+    Some(gap)
   }
 
-  fn read_frame_header(&mut self) -> Result<FrameHeader, X3Error> {
-    self.read_bytes(x3::FrameHeader::LENGTH)?;
-    decoder::read_frame_header(&self.read_buf[0..x3::FrameHeader::LENGTH])
+  ///
+  /// Decode the rest of the archive to a .wav file, with the given options.
+  ///
+  /// ### Arguments
+  ///
+  /// * `wav_filename` - the output wav file to write to.  It will be overwritten.
+  /// * `options` - how gaps between frames and damaged frames are handled.
+  ///
+  /// ### Returns
+  ///
+  /// * the report of the frames that were decoded, and those that couldn't be.
+  ///
+  pub fn decode_to_wav<P: AsRef<path::Path>>(
+    mut self,
+    wav_filename: P,
+    options: &DecodeOptions,
+  ) -> Result<DecodeReport, X3Error> {
+    self.set_concealment(options.concealment, options.best_effort);
+    self.set_salvage(options.salvage);
+
+    let x3_spec = self.spec();
+    let nbits = x3_spec.params.nbits;
+    let spec = hound::WavSpec {
+      channels: x3_spec.channels as u16,
+      sample_rate: x3_spec.sample_rate,
+      bits_per_sample: nbits.div_ceil(8) as u16 * 8,
+      sample_format: hound::SampleFormat::Int,
+    };
+
+    let wav_filename = wav_filename.as_ref();
+    if spec.bits_per_sample == 16 {
+      // The fast path, hound's i16 writer only handles 16-bit wav files
+      write_wav::<i16, R>(&mut self, wav_filename, spec, options, write_samples)?;
+    } else {
+      write_wav::<i32, R>(&mut self, wav_filename, spec, options, write_samples_i32)?;
+    }
+
+    Ok(self.report)
   }
 
-  fn read_frame_payload(&mut self, header: &FrameHeader) -> Result<(), X3Error> {
-    self.read_bytes(header.payload_len)?;
+  ///
+  /// Decode the rest of the archive to a wav or raw stream, such as stdout, which doesn't
+  /// need to seek.  `GapMode::Split` can't split a stream, the gaps are reported.
+  ///
+  /// ### Arguments
+  ///
+  /// * `writer` - where the samples are written.
+  /// * `format` - write a wav stream, or only the samples.
+  /// * `options` - how gaps between frames and damaged frames are handled.
+  ///
+  /// ### Returns
+  ///
+  /// * the report of the frames that were decoded, and those that couldn't be.
+  ///
+  pub fn decode_to_writer<W: Write>(
+    mut self,
+    writer: W,
+    format: StreamFormat,
+    options: &DecodeOptions,
+  ) -> Result<DecodeReport, X3Error> {
+    self.set_concealment(options.concealment, options.best_effort);
+    self.set_salvage(options.salvage);
+
+    let channels = self.spec.channels as usize;
+    let bytes_per_sample = self.spec.params.nbits.div_ceil(8);
+    let mut writer = BufWriter::with_capacity(X3_WRITE_BUFFER_SIZE, writer);
+
+    if format == StreamFormat::Wav {
+      let header = wav_stream_header(channels, self.spec.sample_rate, bytes_per_sample);
+      writer.write_all(&header)?;
+    }
 
-    let payload = &self.read_buf[0..header.payload_len];
-    let crc = crc::crc16(&payload);
+    // 8-bit wav samples are unsigned
+    let offset = if format == StreamFormat::Wav && bytes_per_sample == 1 {
+      128
+    } else {
+      0
+    };
+
+    let mut wav = vec![0i32; X3_WRITE_BUFFER_SIZE];
+    let mut bytes = Vec::with_capacity(X3_WRITE_BUFFER_SIZE * bytes_per_sample);
+    while let Some(samples) = self.decode_next_frame(&mut wav)? {
+      let gap = self.gap().filter(|_| options.gaps == GapMode::ZeroFill);
+      if let Some(gap) = gap {
+        let zero = (offset as u32).to_le_bytes();
+        for _ in 0..gap.samples.max(0) as usize * channels {
+          writer.write_all(&zero[..bytes_per_sample])?;
+        }
+      }
+
+      bytes.clear();
+      for sample in &wav[..samples] {
+        bytes.extend_from_slice(&(sample + offset).to_le_bytes()[..bytes_per_sample]);
+      }
+      writer.write_all(&bytes)?;
+    }
+    writer.flush()?;
+
+    Ok(self.report)
+  }
+
+  //
+  // Read up to `len` bytes to `read_buf` from `start`, there are fewer at the end of the
+  // archive.  The bytes passed over by `resync` are read first.
+  //
+  // ### Return
+  // * the number of bytes read.
+  //
+  fn read_bytes(&mut self, start: usize, len: usize) -> std::io::Result<usize> {
+    let buf = &mut self.read_buf[start..start + len];
+    let mut n = self.pending.len().min(len);
+    buf[..n].copy_from_slice(&self.pending[..n]);
+    self.pending.drain(..n);
+
+    while n < len {
+      match self.reader.read(&mut buf[n..]) {
+        Ok(0) => break,
+        Ok(read) => n += read,
+        Err(err) if err.kind() == ErrorKind::Interrupted => (),
+        Err(err) => return Err(err),
+      }
+    }
+    self.pos += n;
+    Ok(n)
+  }
+
+  //
+  // The payload of the frame in `read_buf`.
+  //
+  fn payload(&self, payload_len: usize) -> &[u8] {
+    &self.read_buf[FrameHeader::LENGTH..FrameHeader::LENGTH + payload_len]
+  }
+
+  fn check_payload(&self, header: &FrameHeader) -> Result<(), X3Error> {
+    let crc = crc::crc16(self.payload(header.payload_len));
     if crc != header.payload_crc {
       return Err(X3Error::FrameHeaderInvalidPayloadCRC);
     }
@@ -102,44 +614,510 @@ impl X3aReader {
     Ok(())
   }
 
-  pub fn decode_next_frame(&mut self, wav_buf: &mut [i16; X3_WRITE_BUFFER_SIZE]) -> Result<Option<usize>, X3Error> {
-    // We have reached the end of the file
-    if self.remaing_bytes <= x3::FrameHeader::LENGTH {
-      return Ok(None);
+  ///
+  /// Decode the next frame, the samples of each channel are interleaved in `wav_buf`.
+  ///
+  /// ### Returns
+  ///
+  /// * the total number of samples written to `wav_buf`, or `None` at the end of the archive.
+  ///
+  pub fn decode_next_frame<S: Sample>(&mut self, wav_buf: &mut [S]) -> Result<Option<usize>, X3Error> {
+    let channels = self.spec.channels as usize;
+    if channels == 1 {
+      return self.decode_next_frame_planar(wav_buf);
+    }
+
+    let mut planar_buf = core::mem::take(&mut self.planar_buf);
+    let mut result = self.decode_next_frame_planar(&mut planar_buf);
+    if let Ok(Some(num_samples)) = result {
+      if wav_buf.len() < num_samples {
+        result = Err(X3Error::FrameDecodeBufferTooSmall);
+      } else if num_samples > 0 {
+        decoder::interleave(&planar_buf[..num_samples], wav_buf, channels);
+      }
+    }
+    self.planar_buf = planar_buf;
+    result
+  }
+
+  ///
+  /// Decode the next frame, the samples are written one channel after the other to `wav_buf`.
+  ///
+  /// ### Returns
+  ///
+  /// * the total number of samples written to `wav_buf`, or `None` at the end of the archive.
+  ///   Each channel holds `num_samples / channels` samples.
+  ///
+  pub fn decode_next_frame_planar<S: Sample>(&mut self, wav_buf: &mut [S]) -> Result<Option<usize>, X3Error> {
+    loop {
+      // Get the header details and the payload, a damaged frame is skipped by finding the
+      // next frame header
+      let pos = self.pos;
+      let frame_header = match self.read_frame_header() {
+        Ok(Some(frame_header)) => frame_header,
+        Ok(None) => return Ok(None),
+        Err(X3Error::Io(err)) => return Err(X3Error::Io(err)),
+        Err(err) => {
+          self.resync_frame(pos, 0, err, FrameHeader::LENGTH)?;
+          continue;
+        }
+      };
+      let payload_len = self.read_bytes(FrameHeader::LENGTH, frame_header.payload_len)?;
+      if payload_len < frame_header.payload_len {
+        // The archive ends part way through the frame
+        if !self.salvage {
+          let samples = frame_header.samples as u64;
+          let error = X3Error::FrameHeaderInvalidPayloadLen;
+          self.bad_frame(pos, samples, error, FrameAction::Dropped);
+          return Ok(None);
+        }
+        return self.salvage_frame(pos, &frame_header, payload_len, wav_buf);
+      }
+
+      // A damaged payload is only decoded when it's concealed
+      let index_frame = frame_header.source_id == x3::FrameHeader::INDEX_SOURCE_ID;
+      let samples = frame_header.samples as usize;
+      let damaged = match self.check_payload(&frame_header) {
+        Ok(()) => None,
+        Err(err) if index_frame || self.concealment == Concealment::Drop => {
+          self.resync_frame(pos, samples as u64, err, FrameHeader::LENGTH + payload_len)?;
+          continue;
+        }
+        Err(err) => Some(err),
+      };
+
+      // Skip the frames of a seek index
+      if index_frame {
+        continue;
+      }
+      self.gap = self.check_time(&frame_header);
+
+      // Do the decoding
+      let x3_bytes = self.payload(payload_len);
+      let channels = frame_header.channels as usize;
+      let result = match damaged {
+        None => decoder::decode_frame(x3_bytes, wav_buf, &self.spec.params, samples, channels),
+        Some(err) => Err(err),
+      };
+      match result.and_then(|decoded| check_decoded(&frame_header, &decoded)) {
+        Err(err @ X3Error::FrameDecodeSampleCount) => {
+          // The number of samples in the header can't be trusted, so it's not concealed
+          self.bad_frame(pos, samples as u64, err, FrameAction::Skipped);
+          continue;
+        }
+        Ok(_) => {
+          self.frame += 1;
+          self.sample += samples as u64;
+          self.report.frames += 1;
+        }
+        Err(X3Error::FrameDecodeBufferTooSmall) => return Err(X3Error::FrameDecodeBufferTooSmall),
+        Err(err) if self.concealment == Concealment::Drop => {
+          // The header is good, so the reader is at the next frame
+          self.bad_frame(pos, samples as u64, err, FrameAction::Skipped);
+          continue;
+        }
+        Err(err) => {
+          if wav_buf.len() < samples * channels {
+            return Err(X3Error::FrameDecodeBufferTooSmall);
+          }
+          let decoded = self.conceal(frame_header.payload_len, wav_buf, samples, channels);
+          self.bad_frame(pos, samples as u64, err, FrameAction::Concealed { decoded });
+        }
+      }
+
+      return Ok(Some(self.output_frame(wav_buf, samples, samples, channels)));
+    }
+  }
+
+  //
+  // Decode the complete blocks of a frame that is cut short by the end of the archive, the
+  // `payload_len` bytes of its payload are in `read_buf`.
+  //
+  fn salvage_frame<S: Sample>(
+    &mut self,
+    pos: usize,
+    header: &FrameHeader,
+    payload_len: usize,
+    wav_buf: &mut [S],
+  ) -> Result<Option<usize>, X3Error> {
+    let samples = header.samples as usize;
+    let channels = header.channels as usize;
+    if wav_buf.len() < samples * channels {
+      return Err(X3Error::FrameDecodeBufferTooSmall);
     }
 
-    // Get the header details
-    let frame_header = self.read_frame_header()?;
-    let samples = frame_header.samples as usize;
-    if self.remaing_bytes < frame_header.payload_len {
+    let decoded = if header.source_id == x3::FrameHeader::INDEX_SOURCE_ID {
+      0
+    } else {
+      self.gap = self.check_time(header);
+      let x3_bytes = self.payload(payload_len);
+      decoder::decode_frame_partial(x3_bytes, wav_buf, &self.spec.params, samples, channels).0
+    };
+    let error = X3Error::FrameHeaderInvalidPayloadLen;
+    self.bad_frame(pos, decoded as u64, error, FrameAction::Salvaged { decoded });
+    Ok(Some(self.output_frame(wav_buf, samples, decoded, channels)))
+  }
+
+  //
+  // Keep the first `n` samples of each channel of a frame of `samples`, less those before
+  // the seek position.
+  //
+  // ### Return
+  // * the total number of samples left in `wav_buf`, one channel after the other.
+  //
+  fn output_frame<S: Sample>(&mut self, wav_buf: &mut [S], samples: usize, n: usize, channels: usize) -> usize {
+    if n > 0 {
+      for (last, ch_wav) in self.last_samples.iter_mut().zip(wav_buf.chunks_exact(samples)) {
+        *last = ch_wav[n - 1].to_i32();
+      }
+    }
+
+    // Drop the samples before the seek position from each channel
+    let skip = core::mem::take(&mut self.skip_samples).min(n);
+    if skip > 0 || n < samples {
+      for c in 0..channels {
+        wav_buf.copy_within(c * samples + skip..c * samples + n, c * (n - skip));
+      }
+    }
+
+    self.report.samples += (n - skip) as u64;
+    (n - skip) * channels
+  }
+
+  //
+  // Replace the samples of a damaged frame as `concealment` says.  With `best_effort` the
+  // payload in `read_buf` is decoded up to the first invalid block first.
+  //
+  // ### Return
+  // * the number of samples of each channel that were decoded.
+  //
+  fn conceal<S: Sample>(&self, payload_len: usize, wav_buf: &mut [S], samples: usize, channels: usize) -> usize {
+    if samples == 0 {
+      return 0;
+    }
+
+    let decoded = if self.best_effort {
+      let x3_bytes = self.payload(payload_len);
+      decoder::decode_frame_partial(x3_bytes, wav_buf, &self.spec.params, samples, channels).0
+    } else {
+      0
+    };
+
+    for (c, ch_wav) in wav_buf[..samples * channels].chunks_exact_mut(samples).enumerate() {
+      let value = match self.concealment {
+        Concealment::Hold if decoded > 0 => ch_wav[decoded - 1],
+        Concealment::Hold => S::from_i32(self.last_samples[c]),
+        _ => S::default(),
+      };
+      ch_wav[decoded..].fill(value);
+    }
+    decoded
+  }
+
+  //
+  // Read the frame header at the reader position, and check it.
+  //
+  // ### Return
+  // * the header, or `None` at the end of the archive.
+  //
+  fn read_frame_header(&mut self) -> Result<Option<FrameHeader>, X3Error> {
+    let pos = self.pos;
+    let len = self.read_bytes(0, FrameHeader::LENGTH)?;
+    if len < FrameHeader::LENGTH {
+      if len > 0 {
+        // There is nothing to salvage from part of a frame header
+        let action = match self.salvage {
+          true => FrameAction::Salvaged { decoded: 0 },
+          false => FrameAction::Dropped,
+        };
+        self.bad_frame(pos, 0, X3Error::FrameDecodeUnexpectedEnd, action);
+      }
       return Ok(None);
     }
 
+    let frame_header = decoder::read_frame_header(&self.read_buf[0..FrameHeader::LENGTH])?;
     if frame_header.payload_len > X3_READ_BUFFER_SIZE {
       // Payload is larger than the available buffer size
       return Err(X3Error::FrameHeaderInvalidPayloadLen);
     }
 
-    // Get the Payload
-    self.read_frame_payload(&frame_header)?;
-    let x3_bytes = &mut self.read_buf[0..frame_header.payload_len];
+    if frame_header.channels != self.spec.channels {
+      return Err(X3Error::FrameHeaderChannelMismatch);
+    }
+
+    Ok(Some(frame_header))
+  }
+
+  //
+  // Add the frame at `pos` to the report of bad frames, it has `samples` in each channel.
+  //
+  fn bad_frame(&mut self, pos: usize, samples: u64, error: X3Error, action: FrameAction) {
+    // A seek to a sample of a frame that is left out goes to the start of the next frame
+    if !matches!(action, FrameAction::Concealed { .. } | FrameAction::Salvaged { .. }) {
+      self.skip_samples = 0;
+    }
+
+    self.report.bad_frames.push(BadFrame {
+      offset: pos as u64,
+      frame: self.frame,
+      sample: self.sample,
+      error,
+      action,
+    });
+    self.frame += 1;
+    self.sample += samples;
+  }
+
+  //
+  // The frame at `pos` is damaged, its first `len` bytes are in `read_buf`.  Continue from
+  // the next good frame header.
+  //
+  fn resync_frame(&mut self, pos: usize, samples: u64, error: X3Error, len: usize) -> Result<(), X3Error> {
+    let next = self.resync(pos, len)?;
+    let action = FrameAction::Resync {
+      skipped_bytes: next - pos,
+    };
+    self.bad_frame(pos, samples, error, action);
+    Ok(())
+  }
+
+  //
+  // Find the next frame after the first byte of the damaged frame at `pos`, the first `len`
+  // bytes of which are in `read_buf`.  This looks for the "x3" key of a frame header with a
+  // good CRC, the bytes from this header on are read again.  The archive is read to the end
+  // when there isn't one.
+  //
+  // ### Return
+  // * the position of the next frame.
+  //
+  fn resync(&mut self, pos: usize, len: usize) -> Result<usize, X3Error> {
+    let mut window = self.read_buf[1..len].to_vec();
+    window.append(&mut self.pending);
+    let mut window_pos = pos + 1;
+
+    loop {
+      // Fill the window
+      let mut end_of_archive = false;
+      while window.len() < X3_READ_BUFFER_SIZE {
+        let start = window.len();
+        window.resize(X3_READ_BUFFER_SIZE, 0);
+        let read = match self.reader.read(&mut window[start..]) {
+          Ok(read) => read,
+          Err(err) if err.kind() == ErrorKind::Interrupted => {
+            window.truncate(start);
+            continue;
+          }
+          Err(err) => return Err(err.into()),
+        };
+        window.truncate(start + read);
+        if read == 0 {
+          end_of_archive = true;
+          break;
+        }
+      }
 
-    // Do the decoding
-    match decoder::decode_frame(x3_bytes, wav_buf, &self.spec.params, samples) {
-      Ok(result) => Ok(result),
-      Err(err) => {
-        self.frame_errors += 1;
-        println!("Frame error: {:?}", err);
-        Ok(None)
+      let mut br = ByteReader::new(&window);
+      while br.find_le_u16(x3::FrameHeader::KEY) {
+        let p = br.get_pos();
+        if p + x3::FrameHeader::LENGTH > window.len() {
+          break;
+        }
+        let header = decoder::read_frame_header(&window[p..p + x3::FrameHeader::LENGTH]);
+        if header.is_ok_and(|h| h.channels == self.spec.channels) {
+          self.pending = window.split_off(p);
+          self.pos = window_pos + p;
+          return Ok(self.pos);
+        }
+        br.set_pos(p + 1);
       }
+
+      if end_of_archive {
+        self.pos = window_pos + window.len();
+        return Ok(self.pos);
+      }
+
+      // A header may cross the end of the window
+      let searched = window.len() - (x3::FrameHeader::LENGTH - 1);
+      window.drain(..searched);
+      window_pos += searched;
+    }
+  }
+}
+
+impl<R: Read + Seek> X3aReader<R> {
+  ///
+  /// Seek to sample `n` of each channel, counted from the start of the archive.  The frame
+  /// headers are read from the first frame until the frame holding the sample is found, the
+  /// next call to `decode_next_frame` decodes this frame and starts at sample `n`.
+  ///
+  /// ### Arguments
+  ///
+  /// * `n` - the sample to seek to.
+  ///
+  pub fn seek_to_sample(&mut self, n: u64) -> Result<(), X3Error> {
+    let from = self.index.as_ref().and_then(|index| index.before_sample(n)).copied();
+    self.seek_frame(from, |header, point| {
+      let samples = header.samples as u64;
+      (n < point.sample + samples).then(|| n - point.sample)
+    })
+  }
+
+  ///
+  /// Seek to the sample at time `t`, or the first sample after it when `t` falls in a gap.
+  /// Frames without a time are timed from the start of the archive.  The next call to
+  /// `decode_next_frame` starts at this sample.
+  ///
+  /// ### Arguments
+  ///
+  /// * `t` - the time to seek to, microseconds since the Unix epoch.
+  ///
+  pub fn seek_to_time(&mut self, t: i64) -> Result<(), X3Error> {
+    let sample_rate = self.spec.sample_rate.max(1) as i64;
+    let from = self.index.as_ref().and_then(|index| index.before_time(t)).copied();
+    self.seek_frame(from, |header, point| {
+      // A frame without samples has nothing to seek to
+      let end = point.time + header.samples as i64 * 1_000_000 / sample_rate;
+      if t >= end || header.samples == 0 {
+        return None;
+      }
+      let skip = (t - point.time).max(0) * sample_rate / 1_000_000;
+      Some((skip as u64).min(header.samples as u64 - 1))
+    })
+  }
+
+  ///
+  /// Read the frame headers and make a seek index, with a seek point every `interval`
+  /// frames.  The reader is left at the start of the archive.
+  ///
+  pub fn build_index(&mut self, interval: usize) -> Result<SeekIndex, X3Error> {
+    let mut points = Vec::new();
+    let mut data_len = self.data_start as u64;
+    let result = self.walk_frames(None, |header, point| {
+      if point.frame % interval.max(1) as u64 == 0 {
+        points.push(*point);
+      }
+      data_len = point.offset + (x3::FrameHeader::LENGTH + header.payload_len) as u64;
+      None
+    });
+    if !matches!(result, Err(X3Error::SeekOutOfRange)) {
+      result?;
     }
+
+    // The last frame may be cut short
+    let archive_len = self.reader.seek(SeekFrom::End(0))?;
+    self.seek_to_sample(0).ok();
+
+    Ok(SeekIndex {
+      points,
+      data_len: data_len.min(archive_len),
+    })
+  }
+
+  //
+  // Seek to the frame found by `walk_frames`, the samples before the target are skipped
+  // when it's decoded.
+  //
+  fn seek_frame(
+    &mut self,
+    from: Option<SeekPoint>,
+    target: impl FnMut(&FrameHeader, &SeekPoint) -> Option<u64>,
+  ) -> Result<(), X3Error> {
+    let (point, skip) = self.walk_frames(from, target)?;
+    self.seek_to_offset(point.offset)?;
+    self.skip_samples = skip as usize;
+    self.frame = point.frame;
+    self.sample = point.sample;
+    self.next_time = None;
+    self.gap = None;
+    Ok(())
+  }
+
+  //
+  // Walk the frame headers, without decoding the payloads, from the seek point `from` or
+  // the first frame.  `target` is given each header and the position of the frame.  It
+  // returns the number of samples to skip once the frame is found.  A damaged header is
+  // passed over to the next good one, as `decode_next_frame` does, and a frame cut short by
+  // the end of the archive is left out.
+  //
+  // ### Return
+  // * the position of the frame that was found, and the number of samples to skip.
+  //
+  fn walk_frames(
+    &mut self,
+    from: Option<SeekPoint>,
+    mut target: impl FnMut(&FrameHeader, &SeekPoint) -> Option<u64>,
+  ) -> Result<(SeekPoint, u64), X3Error> {
+    let sample_rate = self.spec.sample_rate.max(1) as i64;
+    let mut point = from.unwrap_or(SeekPoint {
+      frame: 0,
+      sample: 0,
+      time: 0,
+      offset: self.data_start as u64,
+    });
+    let archive_len = self.reader.seek(SeekFrom::End(0))?;
+    self.seek_to_offset(point.offset)?;
+
+    while self.read_bytes(0, x3::FrameHeader::LENGTH)? == x3::FrameHeader::LENGTH {
+      let header = match decoder::read_frame_header(&self.read_buf[..x3::FrameHeader::LENGTH]) {
+        Ok(header) => header,
+        Err(_) => {
+          let next = self.resync(point.offset as usize, x3::FrameHeader::LENGTH)?;
+          self.seek_to_offset(next as u64)?;
+          point.offset = next as u64;
+          continue;
+        }
+      };
+      let frame_len = (x3::FrameHeader::LENGTH + header.payload_len) as u64;
+      if point.offset + frame_len > archive_len {
+        break;
+      }
+
+      if header.source_id != x3::FrameHeader::INDEX_SOURCE_ID {
+        point.time = frame_time(&header, point.sample, sample_rate);
+        if let Some(skip) = target(&header, &point) {
+          return Ok((point, skip));
+        }
+        point.frame += 1;
+        point.sample += header.samples as u64;
+      }
+
+      self.reader.seek_relative(header.payload_len as i64)?;
+      self.pos += header.payload_len;
+      point.offset += frame_len;
+    }
+
+    Err(X3Error::SeekOutOfRange)
+  }
+
+  //
+  // Move the reader to byte `offset` of the archive.
+  //
+  fn seek_to_offset(&mut self, offset: u64) -> Result<(), X3Error> {
+    self.reader.seek(SeekFrom::Start(offset))?;
+    self.pos = offset as usize;
+    self.pending.clear();
+    Ok(())
+  }
+}
+
+//
+// The time of a frame, frames without a time are timed from the start of the archive.
+//
+fn frame_time(header: &FrameHeader, start: u64, sample_rate: i64) -> i64 {
+  match header.time {
+    0 => start as i64 * 1_000_000 / sample_rate,
+    time => time,
   }
 }
 
 ///
 /// Read the <Archive Header> from in the input buffer.
 ///
-fn read_archive_header(reader: &mut BufReader<File>) -> Result<(X3aSpec, usize), X3Error> {
+/// ### Returns
+///
+/// * the audio properties, the XML archive header and the length of the archive header.
+///
+fn read_archive_header<R: Read>(reader: &mut R) -> Result<(X3aSpec, String, usize), X3Error> {
   // <Archive Id>
   {
     let mut arc_header = [0u8; x3::Archive::ID.len()];
@@ -153,25 +1131,25 @@ fn read_archive_header(reader: &mut BufReader<File>) -> Result<(X3aSpec, usize),
   let header = {
     let mut header_buf = [0u8; x3::FrameHeader::LENGTH];
     reader.read_exact(&mut header_buf)?;
-    decoder::read_frame_header(&mut header_buf)?
+    decoder::read_frame_header(&header_buf)?
   };
 
   // Get the payload
   let mut payload: Vec<u8> = vec![0; header.payload_len];
   reader.read_exact(&mut payload)?;
-  let xml = String::from_utf8_lossy(&payload);
+  // The payload is padded to a whole word
+  let xml: String = String::from_utf8_lossy(&payload).trim_end_matches('\0').into();
 
   let (sample_rate, params) = parse_xml(&xml)?;
 
-  let header_size = x3::FrameHeader::LENGTH + payload.len();
-
   Ok((
     X3aSpec {
       sample_rate,
       params,
-      channels: header.channels,
+      channels: header.channels.max(1),
     },
-    header_size,
+    xml,
+    x3::Archive::ID.len() + x3::FrameHeader::LENGTH + header.payload_len,
   ))
 }
 
@@ -186,46 +1164,364 @@ fn read_archive_header(reader: &mut BufReader<File>) -> Result<(X3aSpec, usize),
 /// * `x3a_filename` - the input X3A file to decode.
 /// * `wav_filename` - the output wav file to write to.  It will be overwritten.
 ///
-pub fn x3a_to_wav<P: AsRef<path::Path>>(x3a_filename: P, wav_filename: P) -> Result<(), X3Error> {
+/// ### Returns
+///
+/// * the report of the frames that were decoded, and those that couldn't be.
+///
+pub fn x3a_to_wav<P: AsRef<path::Path>>(x3a_filename: P, wav_filename: P) -> Result<DecodeReport, X3Error> {
+  x3a_to_wav_with_options(x3a_filename, wav_filename, &DecodeOptions::default())
+}
+
+///
+/// Convert an .x3a (X3 Archive) file to a .wav file, with the given options.
+///
+/// ### Arguments
+///
+/// * `x3a_filename` - the input X3A file to decode.
+/// * `wav_filename` - the output wav file to write to.  It will be overwritten.
+/// * `options` - how gaps between frames and damaged frames are handled.
+///
+/// ### Returns
+///
+/// * the report of the frames that were decoded, and those that couldn't be.
+///
+pub fn x3a_to_wav_with_options<P: AsRef<path::Path>>(
+  x3a_filename: P,
+  wav_filename: P,
+  options: &DecodeOptions,
+) -> Result<DecodeReport, X3Error> {
+  X3aReader::open(x3a_filename)?.decode_to_wav(wav_filename, options)
+}
+
+///
+/// Convert an .x3a (X3 Archive) file to a wav or raw stream, such as stdout, which doesn't
+/// need to seek.  `GapMode::Split` can't split a stream, the gaps are reported.
+///
+/// ### Arguments
+///
+/// * `x3a_filename` - the input X3A file to decode.
+/// * `writer` - where the samples are written.
+/// * `format` - write a wav stream, or only the samples.
+/// * `options` - how gaps between frames and damaged frames are handled.
+///
+/// ### Returns
+///
+/// * the report of the frames that were decoded, and those that couldn't be.
+///
+pub fn x3a_to_writer<P: AsRef<path::Path>, W: Write>(
+  x3a_filename: P,
+  writer: W,
+  format: StreamFormat,
+  options: &DecodeOptions,
+) -> Result<DecodeReport, X3Error> {
+  X3aReader::open(x3a_filename)?.decode_to_writer(writer, format, options)
+}
+
+//
+// The header of a wav stream with the longest data chunk possible, the length of the
+// stream is not known.
+//
+fn wav_stream_header(channels: usize, sample_rate: u32, bytes_per_sample: usize) -> [u8; 44] {
+  let block_align = (channels * bytes_per_sample) as u32;
+  let data_len = (u32::MAX - 36) / block_align * block_align;
+
+  let mut header = [0u8; 44];
+  header[0..4].copy_from_slice(b"RIFF");
+  LittleEndian::write_u32(&mut header[4..], data_len + 36);
+  header[8..16].copy_from_slice(b"WAVEfmt ");
+  LittleEndian::write_u32(&mut header[16..], 16);
+  LittleEndian::write_u16(&mut header[20..], 1); // PCM
+  LittleEndian::write_u16(&mut header[22..], channels as u16);
+  LittleEndian::write_u32(&mut header[24..], sample_rate);
+  LittleEndian::write_u32(&mut header[28..], sample_rate * block_align);
+  LittleEndian::write_u16(&mut header[32..], block_align as u16);
+  LittleEndian::write_u16(&mut header[34..], bytes_per_sample as u16 * 8);
+  header[36..40].copy_from_slice(b"data");
+  LittleEndian::write_u32(&mut header[40..], data_len);
+  header
+}
+
+///
+/// Decode an X3A archive held in memory, such as one from `encode_to_vec`.  The frames must
+/// all be intact, and the samples must fit in 16 bits.  Needs the `std` feature.
+///
+/// ### Arguments
+///
+/// * `x3a_bytes` - the archive, from the archive header to the end of the last frame.
+///
+/// ### Returns
+///
+/// * the audio properties, and the samples with the channels interleaved.
+///
+pub fn decode_from_slice(x3a_bytes: &[u8]) -> Result<(X3aSpec, Vec<i16>), X3Error> {
+  let mut bytes = x3a_bytes;
+  let (mut spec, _, _) = read_archive_header(&mut bytes)?;
+  if spec.params.nbits > 16 {
+    return Err(X3Error::InvalidNBits);
+  }
+
+  let mut wav = Vec::new();
+  let mut planar_buf = vec![0i16; X3_WRITE_BUFFER_SIZE];
+  let mut frames = 0;
+  while !bytes.is_empty() {
+    let header = decoder::read_frame_header(bytes)?;
+    let payload = bytes
+      .get(FrameHeader::LENGTH..FrameHeader::LENGTH + header.payload_len)
+      .ok_or(X3Error::FrameHeaderInvalidPayloadLen)?;
+    if crc::crc16(payload) != header.payload_crc {
+      return Err(X3Error::FrameHeaderInvalidPayloadCRC);
+    }
+    bytes = &bytes[FrameHeader::LENGTH + header.payload_len..];
+
+    // Skip the frames of a seek index
+    if header.source_id == FrameHeader::INDEX_SOURCE_ID {
+      continue;
+    }
+
+    // The number of channels is taken from the first frame
+    if frames == 0 {
+      spec.channels = header.channels;
+    } else if header.channels != spec.channels {
+      return Err(X3Error::FrameHeaderChannelMismatch);
+    }
+    frames += 1;
+
+    let channels = header.channels as usize;
+    let samples = header.samples as usize;
+    let decoded = decoder::decode_frame(payload, &mut planar_buf, &spec.params, samples, channels)?;
+    let num_samples = check_decoded(&header, &decoded)?;
+    if num_samples > 0 {
+      let start = wav.len();
+      wav.resize(start + num_samples, 0);
+      decoder::interleave(&planar_buf[..num_samples], &mut wav[start..], channels);
+    }
+  }
+
+  Ok((spec, wav))
+}
+
+//
+// Check that a frame decoded to the number of samples in its header, and that the payload
+// was all used.  A payload CRC doesn't cover the header, the wrong number of samples
+// decodes to something else.
+//
+// ### Return
+// * the total number of samples of the frame.
+//
+fn check_decoded(header: &FrameHeader, decoded: &decoder::DecodedFrame) -> Result<usize, X3Error> {
+  let samples = header.samples as usize * header.channels as usize;
+  if decoded.samples != samples || decoded.payload_len != header.payload_len {
+    return Err(X3Error::FrameDecodeSampleCount);
+  }
+  Ok(samples)
+}
+
+///
+/// Rewrite an archive that ends part way through a frame, such as when a recorder loses
+/// power, as a valid archive.  The frames before it are copied, and its complete blocks are
+/// encoded again as a shorter frame.  Damaged frames earlier in the archive are copied as
+/// they are.
+///
+/// ### Arguments
+///
+/// * `x3a_filename` - the truncated X3A file.
+/// * `out_filename` - the repaired X3A file to write to.  It will be overwritten.
+///
+/// ### Returns
+///
+/// * the report of the frames that were decoded, the salvaged frame is the last bad frame.
+///
+pub fn repair_x3a<P: AsRef<path::Path>>(x3a_filename: P, out_filename: P) -> Result<DecodeReport, X3Error> {
+  let mut x3a_reader = X3aReader::open(&x3a_filename)?;
+  x3a_reader.set_salvage(true);
+
+  // The salvaged samples are the last ones decoded
+  let mut wav = vec![0i32; X3_WRITE_BUFFER_SIZE];
+  let mut num_samples = 0;
+  while let Some(samples) = x3a_reader.decode_next_frame_planar(&mut wav)? {
+    num_samples = samples;
+  }
+  let report = x3a_reader.report;
+  let (offset, decoded) = match report.bad_frames.last() {
+    Some(BadFrame {
+      offset,
+      action: FrameAction::Salvaged { decoded },
+      ..
+    }) => (*offset, *decoded),
+    _ => {
+      // The archive is complete
+      std::fs::copy(&x3a_filename, &out_filename)?;
+      return Ok(report);
+    }
+  };
+
+  let mut x3a_file = File::open(&x3a_filename)?;
+  let mut header_buf = [0u8; x3::FrameHeader::LENGTH];
+  x3a_file.seek(SeekFrom::Start(offset))?;
+  let time = match x3a_file.read_exact(&mut header_buf) {
+    Ok(()) => decoder::read_frame_header(&header_buf).map_or(0, |h| h.time),
+    Err(_) => 0,
+  };
+
+  // Copy the frames before the truncated one, then write its complete blocks
+  let mut writer = BufWriter::new(File::create(&out_filename)?);
+  x3a_file.rewind()?;
+  std::io::copy(&mut x3a_file.take(offset), &mut writer)?;
+  if decoded > 0 {
+    let channels = x3a_reader.spec.channels as usize;
+    let params = &x3a_reader.spec.params;
+    let byte_writer = &mut StreamByteWriter::new(&mut writer);
+    encoder::encode_frame(&wav[..num_samples], channels, byte_writer, params, time, &mut [0; 6])?;
+  }
+  writer.flush()?;
+
+  Ok(report)
+}
+
+///
+/// Check an archive without writing anything.  The archive header is read, then every
+/// frame header and payload CRC is checked and every frame is decoded.  An archive that
+/// ends part way through a frame fails.
+///
+/// ### Arguments
+///
+/// * `x3a_filename` - the X3A file to check.
+///
+/// ### Returns
+///
+/// * the report of the frames, the archive is good when `DecodeReport::is_ok` is true.
+///
+pub fn verify_x3a<P: AsRef<path::Path>>(x3a_filename: P) -> Result<DecodeReport, X3Error> {
   let mut x3a_reader = X3aReader::open(x3a_filename)?;
+  x3a_reader.set_salvage(true);
+
+  let mut wav = vec![0i32; X3_WRITE_BUFFER_SIZE];
+  while x3a_reader.decode_next_frame_planar(&mut wav)?.is_some() {}
 
-  let x3_spec = x3a_reader.spec();
-  let spec = hound::WavSpec {
-    channels: 1, //x3_spec.channels as u16,
-    sample_rate: x3_spec.sample_rate,
-    bits_per_sample: 16,
-    sample_format: hound::SampleFormat::Int,
+  Ok(x3a_reader.report)
+}
+
+///
+/// Read what an archive holds without decoding it, from the archive header and the frame
+/// headers.  Damaged frame headers are passed over, and a last frame that is cut short is
+/// not counted.
+///
+/// ### Arguments
+///
+/// * `x3a_filename` - the X3A file to read.
+///
+pub fn x3a_info<P: AsRef<path::Path>>(x3a_filename: P) -> Result<ArchiveInfo, X3Error> {
+  let mut x3a_reader = X3aReader::open(&x3a_filename)?;
+  let spec = x3a_reader.spec();
+  let mut info = ArchiveInfo {
+    xml: xml_elements(x3a_reader.xml())?,
+    sample_rate: spec.sample_rate,
+    channels: spec.channels,
+    nbits: spec.params.nbits,
+    index: x3a_reader.index().is_some(),
+    file_len: x3a_reader.reader.get_ref().metadata()?.len(),
+    ..Default::default()
   };
 
+  let sample_rate = spec.sample_rate.max(1) as i64;
+  let result = x3a_reader.walk_frames(None, |header, _| {
+    if info.frames == 0 && header.time != 0 {
+      info.start_time = Some(header.time);
+    }
+    info.end_time = match header.time {
+      0 => None,
+      time => Some(time + header.samples as i64 * 1_000_000 / sample_rate),
+    };
+    if !info.source_ids.contains(&header.source_id) {
+      info.source_ids.push(header.source_id);
+    }
+    info.frames += 1;
+    info.samples += header.samples as u64;
+    None
+  });
+
+  // Every frame was read
+  match result {
+    Err(X3Error::SeekOutOfRange) => Ok(info),
+    Err(err) => Err(err),
+    Ok(_) => Ok(info),
+  }
+}
+
+type WavWriter = hound::WavWriter<std::io::BufWriter<std::fs::File>>;
+
+//
+// Decode all the frames to `wav_filename`, the samples are written with `write`.
+//
+fn write_wav<S: Sample, R: Read>(
+  x3a_reader: &mut X3aReader<R>,
+  wav_filename: &path::Path,
+  spec: hound::WavSpec,
+  options: &DecodeOptions,
+  write: fn(&mut WavWriter, &[S]) -> Result<(), X3Error>,
+) -> Result<(), X3Error> {
+  let channels = spec.channels as usize;
   let mut writer = hound::WavWriter::create(wav_filename, spec)?;
-  let mut wav = [0i16; X3_WRITE_BUFFER_SIZE];
-  loop {
-    match x3a_reader.decode_next_frame(&mut wav)? {
-      Some(samples) => {
-        write_samples(&mut writer, &wav, samples)?;
+  let mut num_files = 1;
+
+  let mut wav = vec![S::default(); X3_WRITE_BUFFER_SIZE];
+  let zeros = [S::default(); 1024];
+  while let Some(samples) = x3a_reader.decode_next_frame(&mut wav)? {
+    if let Some(gap) = x3a_reader.gap() {
+      match options.gaps {
+        GapMode::Report => (),
+        GapMode::ZeroFill => {
+          let mut remaining = gap.samples.max(0) as usize * channels;
+          while remaining > 0 {
+            let len = remaining.min(zeros.len());
+            write(&mut writer, &zeros[..len])?;
+            remaining -= len;
+          }
+        }
+        GapMode::Split => {
+          writer.finalize()?;
+          writer = hound::WavWriter::create(split_filename(wav_filename, num_files), spec)?;
+          num_files += 1;
+        }
       }
-      None => break,
     }
+    write(&mut writer, &wav[..samples])?;
   }
+  writer.finalize()?;
 
   Ok(())
 }
 
-fn write_samples(
-  writer: &mut hound::WavWriter<std::io::BufWriter<std::fs::File>>,
-  buf: &[i16],
-  num_samples: usize,
-) -> Result<(), X3Error> {
-  let mut fast_writer = writer.get_i16_writer(num_samples as u32);
-  for i in 0..num_samples {
+//
+// The name of the `n`th file when the output is split, "out.wav" becomes "out_n.wav".
+//
+fn split_filename(wav_filename: &path::Path, n: usize) -> path::PathBuf {
+  let stem = wav_filename.file_stem().unwrap_or_default().to_string_lossy();
+  let name = match wav_filename.extension() {
+    Some(ext) => format!("{}_{}.{}", stem, n, ext.to_string_lossy()),
+    None => format!("{}_{}", stem, n),
+  };
+  wav_filename.with_file_name(name)
+}
+
+fn write_samples(writer: &mut WavWriter, buf: &[i16]) -> Result<(), X3Error> {
+  let mut fast_writer = writer.get_i16_writer(buf.len() as u32);
+  for sample in buf {
     unsafe {
-      fast_writer.write_sample_unchecked(buf[i]);
+      fast_writer.write_sample_unchecked(*sample);
     }
   }
   fast_writer.flush()?;
   Ok(())
 }
 
+fn write_samples_i32(writer: &mut WavWriter, buf: &[i32]) -> Result<(), X3Error> {
+  for sample in buf {
+    writer.write_sample(*sample)?;
+  }
+  Ok(())
+}
+
 ///
 /// Parse the XML header that contains the parameters for the wav output.
 ///
@@ -238,41 +1534,44 @@ fn parse_xml(xml: &str) -> Result<(u32, x3::Parameters), X3Error> {
   let mut bl = Vec::with_capacity(3);
   let mut codes = Vec::with_capacity(3);
   let mut th = Vec::with_capacity(3);
+  let mut filter = Vec::with_capacity(1);
+  let mut nbits = Vec::with_capacity(1);
 
   // The `Reader` does not implement `Iterator` because it outputs borrowed data (`Cow`s)
   loop {
     match reader.read_event_into(&mut buf) {
-      Ok(Event::Start(ref e)) => match e.name().as_ref() {
-        b"FS" => fs.push(reader.read_text(e.name()).unwrap()),
-        b"BLKLEN" => bl.push(reader.read_text(e.name()).unwrap()),
-        b"CODES" => codes.push(reader.read_text(e.name()).unwrap()),
-        b"T" => th.push(reader.read_text(e.name()).unwrap()),
-        _ => (),
-      },
-      Ok(Event::Eof) => break, // exits the loop when reaching end of file
-      Err(e) => {
-        println!(
-          "Error reading X3 Archive header (XML) at position {}: {:?}",
-          reader.buffer_position(),
-          e
-        );
-        return Err(X3Error::ArchiveHeaderXMLInvalid);
+      Ok(Event::Start(ref e)) => {
+        let values = match e.name().as_ref() {
+          b"FS" => Some(&mut fs),
+          b"BLKLEN" => Some(&mut bl),
+          b"CODES" => Some(&mut codes),
+          b"T" => Some(&mut th),
+          b"FILTER" => Some(&mut filter),
+          b"NBITS" => Some(&mut nbits),
+          _ => None,
+        };
+        if let Some(values) = values {
+          values.push(
+            reader
+              .read_text(e.name())
+              .map_err(|_| X3Error::ArchiveHeaderXMLInvalid)?,
+          );
+        }
       }
+      Ok(Event::Eof) => break, // exits the loop when reaching end of file
+      Err(_) => return Err(X3Error::ArchiveHeaderXMLInvalid),
       _ => (), // There are several other `Event`s we do not consider here
     }
 
     // if we don't keep a borrow elsewhere, we can clear the buffer to keep memory usage low
     buf.clear();
   }
-  println!("sample rate: {}", fs[0]);
-  println!("block length: {}", bl[0]);
-  println!("Rice codes: {}", codes[0]);
-  println!("thresholds: {}", th[0]);
-
-  let sample_rate = fs[0].parse::<u32>().unwrap();
-  let block_len = bl[0].parse::<u32>().unwrap();
+  // Each of these must be given, and be valid
+  let invalid = |_| X3Error::ArchiveHeaderXMLInvalid;
+  let sample_rate = first(&fs)?.parse::<u32>().map_err(invalid)?;
+  let block_len = first(&bl)?.parse::<u32>().map_err(invalid)?;
   let mut rice_code_ids = Vec::new();
-  for word in codes[0].split(',') {
+  for word in first(&codes)?.split(',') {
     match word {
       "RICE0" => rice_code_ids.push(0),
       "RICE1" => rice_code_ids.push(1),
@@ -282,26 +1581,105 @@ fn parse_xml(xml: &str) -> Result<(u32, x3::Parameters), X3Error> {
       _ => return Err(X3Error::ArchiveHeaderXMLRiceCode),
     };
   }
-  let thresholds: Vec<usize> = th[0].split(',').map(|s| s.parse::<usize>().unwrap()).collect();
-
-  let mut rc_array: [usize; 3] = [0; 3];
-  let mut th_array: [usize; 3] = [0; 3];
+  let thresholds = first(&th)?
+    .split(',')
+    .map(|s| s.parse::<usize>().map_err(invalid))
+    .collect::<Result<Vec<_>, _>>()?;
 
-  #[allow(clippy::manual_memcpy)]
-  for i in 0..3 {
-    rc_array[i] = rice_code_ids[i];
-    th_array[i] = thresholds[i];
-  }
-  let params = x3::Parameters::new(
+  let rc_array: [usize; 3] = rice_code_ids
+    .try_into()
+    .map_err(|_| X3Error::ArchiveHeaderXMLRiceCode)?;
+  let th_array: [usize; 3] = thresholds.try_into().map_err(|_| X3Error::ArchiveHeaderXMLInvalid)?;
+  let mut params = x3::Parameters::new(
     block_len as usize,
     x3::Parameters::DEFAULT_BLOCKS_PER_FRAME,
     rc_array,
     th_array,
   )?;
 
+  if let Some(nbits) = nbits.first() {
+    params.nbits = nbits.parse::<usize>().map_err(|_| X3Error::ArchiveHeaderXMLInvalid)?;
+    params.check_nbits()?;
+  }
+
+  // Older archives don't have a filter, these are "DIFF"
+  if let Some(filter) = filter.first() {
+    let mut words = filter.split(',');
+    params.filter = words
+      .next()
+      .and_then(x3::Filter::from_name)
+      .ok_or(X3Error::ArchiveHeaderXMLFilter)?;
+    for word in words {
+      match word {
+        "STEREO" => params.decorrelate = true,
+        "SHIFT" => params.wasted_bits = true,
+        _ => return Err(X3Error::ArchiveHeaderXMLFilter),
+      }
+    }
+  }
+
   Ok((sample_rate, params))
 }
 
+//
+// The text of the first of the elements, it's missing when there are none.
+//
+fn first<'a>(values: &'a [std::borrow::Cow<'_, str>]) -> Result<&'a str, X3Error> {
+  values
+    .first()
+    .map(|v| v.as_ref())
+    .ok_or(X3Error::ArchiveHeaderXMLInvalid)
+}
+
+//
+// The elements of the XML archive header, in the order they start.
+//
+fn xml_elements(xml: &str) -> Result<Vec<XmlElement>, X3Error> {
+  let mut reader = Reader::from_str(xml);
+  reader.config_mut().trim_text(true);
+
+  let mut elements: Vec<XmlElement> = Vec::new();
+  let mut open: Vec<usize> = Vec::new();
+  loop {
+    let (e, empty) = match reader.read_event() {
+      Ok(Event::Start(e)) => (e, false),
+      Ok(Event::Empty(e)) => (e, true),
+      Ok(Event::Text(text)) => {
+        if let Some(&i) = open.last() {
+          elements[i].text = text.decode().map_err(|_| X3Error::ArchiveHeaderXMLInvalid)?.into();
+        }
+        continue;
+      }
+      Ok(Event::End(_)) => {
+        open.pop();
+        continue;
+      }
+      Ok(Event::Eof) => break,
+      Err(_) => return Err(X3Error::ArchiveHeaderXMLInvalid),
+      _ => continue,
+    };
+
+    let mut attributes = Vec::new();
+    for attr in e.attributes() {
+      let attr = attr.map_err(|_| X3Error::ArchiveHeaderXMLInvalid)?;
+      let value = attr.unescape_value().map_err(|_| X3Error::ArchiveHeaderXMLInvalid)?;
+      attributes.push((String::from_utf8_lossy(attr.key.as_ref()).into(), value.into()));
+    }
+    let depth = open.len();
+    if !empty {
+      open.push(elements.len());
+    }
+    elements.push(XmlElement {
+      depth,
+      name: String::from_utf8_lossy(e.name().as_ref()).into(),
+      attributes,
+      text: String::new(),
+    });
+  }
+
+  Ok(elements)
+}
+
 //
 //
 //            #######
@@ -316,10 +1694,732 @@ fn parse_xml(xml: &str) -> Result<(u32, x3::Parameters), X3Error> {
 
 #[cfg(test)]
 mod tests {
+  use crate::chrono::DateTime;
+  use crate::decodefile::{
+    decode_from_slice, repair_x3a, verify_x3a, x3a_info, x3a_to_wav_with_options, x3a_to_writer, Concealment,
+    DecodeOptions, FrameAction, Gap, GapMode, StreamFormat, X3aReader,
+  };
+  use crate::decoder::read_frame_header;
+  use crate::encodefile::{encode_channels_to_vec, encode_to_vec, wav_to_x3a_stream, EncodeOptions};
+  use crate::encoder::write_frame_header;
+  use crate::error::X3Error;
+  use crate::hound;
+  use crate::seekindex::{self, IndexLocation};
+  use crate::utils::TempFile;
+  use crate::x3::{self, FrameHeader};
+  use std::io::{Cursor, Read};
+  use std::vec::Vec;
+  use std::{format, fs, vec};
+
   // use crate::decodefile::x3a_to_wav;
 
   // #[test]
   // fn test_decode_x3a_file() {
   //   x3a_to_wav("~/tmp/test.x3a", "~/tmp/test.wav").unwrap();
   // }
+
+  //
+  // Encode 3000 samples at 8 kHz as three frames, then move the last two frames 100 ms
+  // (800 samples) later.
+  //
+  fn archive_with_gap() -> (Vec<u8>, Vec<i16>) {
+    let wav: Vec<i16> = (0..3000).map(|i| ((i * 37) % 101) as i16 - 50).collect();
+    let spec = hound::WavSpec {
+      channels: 1,
+      sample_rate: 8000,
+      bits_per_sample: 16,
+      sample_format: hound::SampleFormat::Int,
+    };
+    let mut wav_bytes = Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut wav_bytes, spec).unwrap();
+    for sample in &wav {
+      writer.write_sample(*sample).unwrap();
+    }
+    writer.finalize().unwrap();
+
+    let options = EncodeOptions {
+      params: x3::Parameters::new(20, 50, [0, 1, 3], [3, 8, 20]).unwrap(),
+      start_time: DateTime::from_timestamp(1_590_969_600, 0),
+      ..Default::default()
+    };
+    let mut bytes = Vec::new();
+    wav_to_x3a_stream(&wav_bytes.into_inner()[..], &mut bytes, &options).unwrap();
+
+    for p in frame_offsets(&bytes).into_iter().skip(1) {
+      let header = read_frame_header(&bytes[p..]).unwrap();
+      let time = header.time + 100_000;
+      let new_header = write_frame_header(
+        header.samples as usize,
+        header.source_id,
+        header.channels,
+        header.payload_len,
+        header.payload_crc,
+        time,
+      );
+      bytes[p..p + FrameHeader::LENGTH].copy_from_slice(&new_header);
+    }
+
+    (bytes, wav)
+  }
+
+  //
+  // The position of each frame of an archive, after the archive header.
+  //
+  fn frame_offsets(bytes: &[u8]) -> Vec<usize> {
+    let archive_header = read_frame_header(&bytes[x3::Archive::ID.len()..]).unwrap();
+    let mut p = x3::Archive::ID.len() + FrameHeader::LENGTH + archive_header.payload_len;
+    let mut offsets = Vec::new();
+    while p + FrameHeader::LENGTH <= bytes.len() {
+      offsets.push(p);
+      p += FrameHeader::LENGTH + read_frame_header(&bytes[p..]).unwrap().payload_len;
+    }
+    offsets
+  }
+
+  //
+  // The archive from `archive_with_gap`, in a file of its own.
+  //
+  fn write_archive_with_gap(name: &str) -> (TempFile, Vec<i16>) {
+    let (bytes, wav) = archive_with_gap();
+    let x3a_path = TempFile::new(&format!("{}.x3a", name));
+    fs::write(&x3a_path, bytes).unwrap();
+    (x3a_path, wav)
+  }
+
+  fn read_wav(path: &TempFile) -> Vec<i16> {
+    hound::WavReader::open(path)
+      .unwrap()
+      .samples::<i16>()
+      .map(|s| s.unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn test_x3a_reader_gap() {
+    let (x3a_path, _) = write_archive_with_gap("reader-gap");
+    let mut reader = X3aReader::open(&x3a_path).unwrap();
+    let wav = &mut [0i16; 2000];
+
+    let mut gaps = Vec::new();
+    while reader.decode_next_frame(wav).unwrap().is_some() {
+      gaps.push(reader.gap());
+    }
+    let gap = Gap {
+      expected_time: 1_590_969_600_125_000,
+      time: 1_590_969_600_225_000,
+      samples: 800,
+    };
+    assert_eq!(vec![None, Some(gap), None], gaps);
+
+    // The gaps are in the report, they are not damage
+    assert_eq!(vec![gap], reader.report().gaps);
+    assert!(reader.report().is_ok());
+  }
+
+  #[test]
+  fn test_x3a_reader_seek() {
+    let (x3a_path, wav) = write_archive_with_gap("reader-seek");
+    let mut reader = X3aReader::open(&x3a_path).unwrap();
+    let buf = &mut [0i16; 2000];
+
+    // Into the second frame
+    reader.seek_to_sample(1234).unwrap();
+    assert_eq!(Some(766), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(&wav[1234..2000], &buf[..766]);
+    assert_eq!(Some(1000), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(&wav[2000..], &buf[..1000]);
+    assert_eq!(None, reader.decode_next_frame(buf).unwrap());
+
+    // Backwards, to the start of a frame
+    reader.seek_to_sample(0).unwrap();
+    assert_eq!(Some(1000), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(&wav[..1000], &buf[..1000]);
+    assert!(matches!(reader.seek_to_sample(3000), Err(X3Error::SeekOutOfRange)));
+
+    // 50 ms into the first frame
+    reader.seek_to_time(1_590_969_600_050_000).unwrap();
+    assert_eq!(Some(600), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(&wav[400..1000], &buf[..600]);
+
+    // In the gap after the first frame, the second frame starts at 225 ms
+    reader.seek_to_time(1_590_969_600_150_000).unwrap();
+    assert_eq!(Some(1000), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(&wav[1000..2000], &buf[..1000]);
+    reader.seek_to_time(1_590_969_600_250_000).unwrap();
+    assert_eq!(Some(800), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(&wav[1200..2000], &buf[..800]);
+
+    // A second frame without samples, the seek goes on to the third frame
+    let mut bytes = fs::read(&x3a_path).unwrap();
+    let frame_2 = frame_offsets(&bytes)[1];
+    let header = read_frame_header(&bytes[frame_2..]).unwrap();
+    let new_header = write_frame_header(
+      0,
+      header.source_id,
+      header.channels,
+      header.payload_len,
+      header.payload_crc,
+      header.time,
+    );
+    bytes[frame_2..frame_2 + FrameHeader::LENGTH].copy_from_slice(&new_header);
+    let mut reader = X3aReader::new(Cursor::new(&bytes)).unwrap();
+    reader.seek_to_time(1_590_969_600_150_000).unwrap();
+    assert_eq!(Some(1000), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(&wav[2000..], &buf[..1000]);
+  }
+
+  #[test]
+  fn test_x3a_reader_seek_index() {
+    for location in [IndexLocation::Sidecar, IndexLocation::Embedded] {
+      let (x3a_path, wav) = write_archive_with_gap("reader-seek-index");
+      seekindex::write_index(&x3a_path, location, 2).unwrap();
+      let _sidecar = TempFile(seekindex::sidecar_path(&x3a_path));
+      let mut reader = X3aReader::open(&x3a_path).unwrap();
+      let buf = &mut [0i16; 2000];
+
+      let index = reader.index().unwrap();
+      assert_eq!(vec![0, 2000], index.points.iter().map(|p| p.sample).collect::<Vec<_>>());
+      assert_eq!(1_590_969_600_350_000, index.points[1].time);
+
+      // From a seek point
+      reader.seek_to_sample(2500).unwrap();
+      assert_eq!(Some(500), reader.decode_next_frame(buf).unwrap());
+      assert_eq!(&wav[2500..], &buf[..500]);
+      assert_eq!(None, reader.decode_next_frame(buf).unwrap());
+
+      // Between seek points
+      reader.seek_to_time(1_590_969_600_250_000).unwrap();
+      assert_eq!(Some(800), reader.decode_next_frame(buf).unwrap());
+      assert_eq!(&wav[1200..2000], &buf[..800]);
+
+      // The index frames are not audio
+      let out_path = TempFile::new("seek-index.wav");
+      x3a_to_wav_with_options(&x3a_path, &out_path, &DecodeOptions::default()).unwrap();
+      assert_eq!(wav, read_wav(&out_path));
+    }
+  }
+
+  #[test]
+  fn test_x3a_reader_seek_index_bad_sidecar() {
+    let (x3a_path, _) = write_archive_with_gap("reader-bad-sidecar");
+    let sidecar = TempFile(seekindex::sidecar_path(&x3a_path));
+    seekindex::write_index(&x3a_path, IndexLocation::Sidecar, 1).unwrap();
+    let bytes = fs::read(&sidecar).unwrap();
+    seekindex::write_index(&x3a_path, IndexLocation::Embedded, 2).unwrap();
+
+    // The sidecar has a seek point every frame, a damaged or cut short one is not used
+    let mut damaged = bytes.clone();
+    damaged[FrameHeader::LENGTH + 10] ^= 1;
+    for sidecar_bytes in [&damaged[..], &bytes[..bytes.len() - 1]] {
+      fs::write(&sidecar, sidecar_bytes).unwrap();
+      let reader = X3aReader::open(&x3a_path).unwrap();
+      let index = reader.index().unwrap();
+      assert_eq!(vec![0, 2000], index.points.iter().map(|p| p.sample).collect::<Vec<_>>());
+    }
+  }
+
+  #[test]
+  fn test_x3a_reader_resync() {
+    let (x3a_path, wav) = write_archive_with_gap("reader-resync");
+    let bytes = fs::read(&x3a_path).unwrap();
+    let frames = frame_offsets(&bytes);
+    let (frame_2, frame_3) = (frames[1], frames[2]);
+
+    // A bit flip in the header of the second frame, then in its payload
+    for p in [frame_2 + FrameHeader::P_SAMPLES, frame_2 + FrameHeader::LENGTH + 100] {
+      let mut damaged = bytes.clone();
+      damaged[p] ^= 0x10;
+      fs::write(&x3a_path, &damaged).unwrap();
+
+      let mut reader = X3aReader::open(&x3a_path).unwrap();
+      let buf = &mut [0i16; 2000];
+      let mut decoded = Vec::new();
+      while let Some(samples) = reader.decode_next_frame(buf).unwrap() {
+        decoded.extend_from_slice(&buf[..samples]);
+      }
+      assert_eq!(&wav[..1000], &decoded[..1000]);
+      assert_eq!(&wav[2000..], &decoded[1000..]);
+
+      let report = reader.report();
+      assert_eq!((2, 2000), (report.frames, report.samples));
+      assert_eq!(1, report.bad_frames.len());
+      let bad_frame = &report.bad_frames[0];
+      assert_eq!(
+        (frame_2 as u64, 1, 1000),
+        (bad_frame.offset, bad_frame.frame, bad_frame.sample)
+      );
+      let skipped_bytes = frame_3 - frame_2;
+      assert_eq!(FrameAction::Resync { skipped_bytes }, bad_frame.action);
+      if p < frame_2 + FrameHeader::LENGTH {
+        assert!(matches!(bad_frame.error, X3Error::FrameHeaderInvalidHeaderCRC));
+      } else {
+        assert!(matches!(bad_frame.error, X3Error::FrameHeaderInvalidPayloadCRC));
+      }
+
+      // Seeking passes over the damaged frame, 50 ms into the third frame
+      reader.seek_to_time(1_590_969_600_400_000).unwrap();
+      assert_eq!(Some(600), reader.decode_next_frame(buf).unwrap());
+      assert_eq!(&wav[2400..], &buf[..600]);
+    }
+
+    // The report is returned from x3a_to_wav
+    let out_path = TempFile::new("resync.wav");
+    let report = x3a_to_wav_with_options(&x3a_path, &out_path, &DecodeOptions::default()).unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(2000, read_wav(&out_path).len());
+  }
+
+  #[test]
+  fn test_x3a_reader_in_memory() {
+    // A stream that can't seek, and gives a few bytes at a time
+    struct Trickle<'a>(&'a [u8]);
+    impl Read for Trickle<'_> {
+      fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.0.len()).min(7);
+        buf[..n].copy_from_slice(&self.0[..n]);
+        self.0 = &self.0[n..];
+        Ok(n)
+      }
+    }
+
+    let (x3a_path, wav) = write_archive_with_gap("reader-in-memory");
+    let mut bytes = fs::read(&x3a_path).unwrap();
+    let buf = &mut [0i16; 2000];
+
+    // Seek in a buffer
+    let mut reader = X3aReader::new(Cursor::new(&bytes)).unwrap();
+    reader.seek_to_sample(1234).unwrap();
+    assert_eq!(Some(766), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(&wav[1234..2000], &buf[..766]);
+
+    // A damaged second frame is skipped without seeking back
+    let frame_2 = frame_offsets(&bytes)[1];
+    bytes[frame_2 + FrameHeader::LENGTH + 100] ^= 0x10;
+
+    // A seek into the damaged frame starts at the next good frame, or in the concealed frame
+    let mut reader = X3aReader::new(Cursor::new(&bytes)).unwrap();
+    reader.seek_to_sample(1234).unwrap();
+    assert_eq!(Some(1000), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(&wav[2000..], &buf[..1000]);
+    reader.set_concealment(Concealment::Zero, false);
+    reader.seek_to_sample(1234).unwrap();
+    assert_eq!(Some(766), reader.decode_next_frame(buf).unwrap());
+    assert!(buf[..766].iter().all(|&s| s == 0));
+
+    let mut reader = X3aReader::new(Trickle(&bytes)).unwrap();
+    assert_eq!(1, reader.spec().channels);
+    let mut decoded = Vec::new();
+    while let Some(samples) = reader.decode_next_frame(buf).unwrap() {
+      decoded.extend_from_slice(&buf[..samples]);
+    }
+    assert_eq!(&wav[..1000], &decoded[..1000]);
+    assert_eq!(&wav[2000..], &decoded[1000..]);
+    let report = reader.report();
+    assert_eq!((2, 1), (report.frames, report.bad_frames.len()));
+    assert_eq!(frame_2 as u64, report.bad_frames[0].offset);
+  }
+
+  #[test]
+  fn test_x3a_reader_conceal() {
+    let (x3a_path, wav) = write_archive_with_gap("reader-conceal");
+    let mut bytes = fs::read(&x3a_path).unwrap();
+    let frame_2 = frame_offsets(&bytes)[1];
+
+    // Zeros in the payload of the second frame, from 100 bytes in, give an invalid block
+    let p = frame_2 + FrameHeader::LENGTH + 100;
+    bytes[p..p + 32].fill(0);
+    fs::write(&x3a_path, &bytes).unwrap();
+
+    for (concealment, best_effort) in [
+      (Concealment::Zero, false),
+      (Concealment::Hold, false),
+      (Concealment::Zero, true),
+      (Concealment::Hold, true),
+    ] {
+      let mut reader = X3aReader::open(&x3a_path).unwrap();
+      reader.set_concealment(concealment, best_effort);
+      let buf = &mut [0i16; 2000];
+      let mut decoded = Vec::new();
+      while let Some(samples) = reader.decode_next_frame(buf).unwrap() {
+        decoded.extend_from_slice(&buf[..samples]);
+      }
+
+      // The frames after the damaged one keep their sample position
+      assert_eq!(3000, decoded.len());
+      assert_eq!(&wav[..1000], &decoded[..1000]);
+      assert_eq!(&wav[2000..], &decoded[2000..]);
+
+      let report = reader.report();
+      assert_eq!((2, 3000), (report.frames, report.samples));
+      assert_eq!(1, report.bad_frames.len());
+      assert!(matches!(
+        report.bad_frames[0].error,
+        X3Error::FrameHeaderInvalidPayloadCRC
+      ));
+      let n = match report.bad_frames[0].action {
+        FrameAction::Concealed { decoded } => 1000 + decoded,
+        _ => panic!("The frame was not concealed"),
+      };
+      if best_effort {
+        assert!(n > 1020 && n < 2000);
+      } else {
+        assert_eq!(1000, n);
+      }
+      // The damage can start in the last block that was decoded
+      let good = n.saturating_sub(20).max(1000);
+      assert_eq!(&wav[1000..good], &decoded[1000..good]);
+      let value = match concealment {
+        Concealment::Hold => decoded[n - 1],
+        _ => 0,
+      };
+      assert!(decoded[n..2000].iter().all(|&s| s == value));
+    }
+
+    // Dropped by default
+    let out_path = TempFile::new("conceal.wav");
+    let options = DecodeOptions {
+      concealment: Concealment::Hold,
+      ..Default::default()
+    };
+    x3a_to_wav_with_options(&x3a_path, &out_path, &options).unwrap();
+    assert_eq!(3000, read_wav(&out_path).len());
+    x3a_to_wav_with_options(&x3a_path, &out_path, &DecodeOptions::default()).unwrap();
+    assert_eq!(2000, read_wav(&out_path).len());
+  }
+
+  #[test]
+  fn test_x3a_reader_conceal_no_samples() {
+    // The header of the second frame says it has no samples, and its payload is damaged
+    let (mut bytes, wav) = archive_with_gap();
+    let frame_2 = frame_offsets(&bytes)[1];
+    let header = read_frame_header(&bytes[frame_2..]).unwrap();
+    let new_header = write_frame_header(
+      0,
+      header.source_id,
+      header.channels,
+      header.payload_len,
+      header.payload_crc ^ 1,
+      header.time,
+    );
+    bytes[frame_2..frame_2 + FrameHeader::LENGTH].copy_from_slice(&new_header);
+
+    let mut reader = X3aReader::new(&bytes[..]).unwrap();
+    reader.set_concealment(Concealment::Zero, false);
+    let buf = &mut [0i16; 2000];
+    assert_eq!(Some(1000), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(Some(0), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(Some(1000), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(&wav[2000..], &buf[..1000]);
+    let bad_frame = &reader.report().bad_frames[0];
+    assert_eq!(FrameAction::Concealed { decoded: 0 }, bad_frame.action);
+  }
+
+  #[test]
+  fn test_x3a_reader_salvage() {
+    let (x3a_path, wav) = write_archive_with_gap("reader-salvage");
+    let bytes = fs::read(&x3a_path).unwrap();
+    let frame_3 = frame_offsets(&bytes)[2];
+
+    // Cut short half way through the payload of the last frame
+    let len = bytes.len() - (bytes.len() - frame_3 - FrameHeader::LENGTH) / 2;
+    fs::write(&x3a_path, &bytes[..len]).unwrap();
+    for salvage in [false, true] {
+      let mut reader = X3aReader::open(&x3a_path).unwrap();
+      reader.set_salvage(salvage);
+      let buf = &mut [0i16; 2000];
+      let mut decoded = Vec::new();
+      while let Some(samples) = reader.decode_next_frame(buf).unwrap() {
+        decoded.extend_from_slice(&buf[..samples]);
+      }
+      assert_eq!(&wav[..decoded.len()], &decoded[..]);
+
+      let report = reader.report();
+      assert_eq!(decoded.len() as u64, report.samples);
+      assert!(!report.is_ok());
+      assert_eq!(1, report.bad_frames.len());
+      let bad_frame = &report.bad_frames[0];
+      assert_eq!(
+        (frame_3 as u64, 2, 2000),
+        (bad_frame.offset, bad_frame.frame, bad_frame.sample)
+      );
+      assert!(matches!(bad_frame.error, X3Error::FrameHeaderInvalidPayloadLen));
+      if !salvage {
+        assert_eq!(2000, decoded.len());
+        assert_eq!(FrameAction::Dropped, bad_frame.action);
+        continue;
+      }
+      assert!(decoded.len() > 2000 && decoded.len() < 3000);
+      let decoded = decoded.len() - 2000;
+      assert_eq!(FrameAction::Salvaged { decoded }, bad_frame.action);
+    }
+
+    // Repair, the salvaged samples are in a shorter last frame
+    let repaired_path = TempFile::new("repaired.x3a");
+    let report = repair_x3a(&x3a_path, &repaired_path).unwrap();
+    let salvaged = match report.bad_frames[0].action {
+      FrameAction::Salvaged { decoded } => decoded,
+      _ => panic!("The frame was not salvaged"),
+    };
+    let mut reader = X3aReader::open(&repaired_path).unwrap();
+    let buf = &mut [0i16; 2000];
+    let mut decoded = Vec::new();
+    let mut gaps = Vec::new();
+    while let Some(samples) = reader.decode_next_frame(buf).unwrap() {
+      decoded.extend_from_slice(&buf[..samples]);
+      gaps.push(reader.gap());
+    }
+    assert!(reader.report().is_ok());
+    assert_eq!(&wav[..2000 + salvaged], &decoded[..]);
+    assert_eq!(
+      vec![false, true, false],
+      gaps.iter().map(Option::is_some).collect::<Vec<_>>()
+    );
+
+    // Cut short in the header of the last frame, there is nothing to salvage
+    fs::write(&x3a_path, &bytes[..frame_3 + 10]).unwrap();
+    let report = repair_x3a(&x3a_path, &repaired_path).unwrap();
+    assert!(matches!(report.bad_frames[0].error, X3Error::FrameDecodeUnexpectedEnd));
+    assert_eq!(&bytes[..frame_3], &fs::read(&repaired_path).unwrap()[..]);
+
+    // A complete archive is copied
+    fs::write(&x3a_path, &bytes).unwrap();
+    assert!(repair_x3a(&x3a_path, &repaired_path).unwrap().is_ok());
+    assert_eq!(bytes, fs::read(&repaired_path).unwrap());
+  }
+
+  #[test]
+  fn test_x3a_reader_salvage_stereo() {
+    let wav: Vec<i16> = (0..6000).map(|i| ((i * 37) % 101) as i16 - 50).collect();
+    let params = x3::Parameters::new(20, 50, [0, 1, 3], [3, 8, 20]).unwrap();
+    let x3a = encode_channels_to_vec(&wav, 2, 8000, params).unwrap();
+    let frame_2 = frame_offsets(&x3a)[1];
+
+    // Cut short one byte into the payload of the second frame, no samples are salvaged
+    let cut = &x3a[..frame_2 + FrameHeader::LENGTH + 1];
+    let mut reader = X3aReader::new(cut).unwrap();
+    reader.set_salvage(true);
+    let buf = &mut [0i16; 2000];
+    assert_eq!(Some(1000), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(&wav[..1000], &buf[..1000]);
+    assert_eq!(Some(0), reader.decode_next_frame(buf).unwrap());
+    assert_eq!(None, reader.decode_next_frame(buf).unwrap());
+    let bad_frame = &reader.report().bad_frames[0];
+    assert_eq!(FrameAction::Salvaged { decoded: 0 }, bad_frame.action);
+
+    // No channels in the first frame header
+    let mut no_channels = x3a.clone();
+    let p = frame_offsets(&x3a)[0];
+    let header = read_frame_header(&x3a[p..]).unwrap();
+    let new_header = write_frame_header(
+      header.samples as usize,
+      header.source_id,
+      0,
+      header.payload_len,
+      header.payload_crc,
+      header.time,
+    );
+    no_channels[p..p + FrameHeader::LENGTH].copy_from_slice(&new_header);
+    assert!(matches!(
+      X3aReader::new(&no_channels[..]),
+      Err(X3Error::InvalidNumChannels)
+    ));
+  }
+
+  #[test]
+  fn test_verify_x3a() {
+    let (x3a_path, _) = write_archive_with_gap("verify");
+    let bytes = fs::read(&x3a_path).unwrap();
+
+    let report = verify_x3a(&x3a_path).unwrap();
+    assert!(report.is_ok());
+    assert_eq!((3, 3000), (report.frames, report.samples));
+
+    // A damaged payload
+    let mut damaged = bytes.clone();
+    let len = damaged.len();
+    damaged[len - 100] ^= 0x01;
+    fs::write(&x3a_path, &damaged).unwrap();
+    let report = verify_x3a(&x3a_path).unwrap();
+    assert_eq!(1, report.bad_frames.len());
+    assert!(matches!(
+      report.bad_frames[0].error,
+      X3Error::FrameHeaderInvalidPayloadCRC
+    ));
+
+    // Cut short
+    fs::write(&x3a_path, &bytes[..bytes.len() - 1]).unwrap();
+    let report = verify_x3a(&x3a_path).unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(2, report.frames);
+
+    // Not an archive
+    fs::write(&x3a_path, &bytes[..10]).unwrap();
+    assert!(verify_x3a(&x3a_path).is_err());
+  }
+
+  #[test]
+  fn test_x3a_info() {
+    let (x3a_path, _) = write_archive_with_gap("info");
+    let info = x3a_info(&x3a_path).unwrap();
+
+    assert_eq!((8000, 1, 16), (info.sample_rate, info.channels, info.nbits));
+    assert_eq!((3, 3000), (info.frames, info.samples));
+    assert_eq!(0.375, info.duration());
+    assert_eq!(Some(1_590_969_600_000_000), info.start_time);
+    assert_eq!(Some(1_590_969_600_475_000), info.end_time);
+    assert_eq!(vec![1], info.source_ids);
+    assert!(!info.index);
+    assert_eq!(fs::metadata(&x3a_path).unwrap().len(), info.file_len);
+    assert!(info.compression_ratio() > 1.0);
+
+    let prog = &info.xml[0];
+    assert_eq!(("X3ARCH", 0), (prog.name.as_str(), prog.depth));
+    assert_eq!(("PROG".into(), "x3new.m".into()), prog.attributes[0]);
+    let fs = info.xml.iter().find(|e| e.name == "FS").unwrap();
+    assert_eq!(("8000", 1), (fs.text.as_str(), fs.depth));
+    let codes = info.xml.iter().find(|e| e.name == "CODES").unwrap();
+    assert_eq!(("RICE0,RICE1,RICE3,BFP", 2), (codes.text.as_str(), codes.depth));
+
+    // A damaged header of the second frame, it's passed over
+    let bytes = fs::read(&x3a_path).unwrap();
+    let frame_2 = frame_offsets(&bytes)[1];
+    let mut damaged = bytes.clone();
+    damaged[frame_2 + FrameHeader::P_SAMPLES] ^= 0x10;
+    fs::write(&x3a_path, &damaged).unwrap();
+    let info = x3a_info(&x3a_path).unwrap();
+    assert_eq!((2, 2000), (info.frames, info.samples));
+    assert_eq!(Some(1_590_969_600_475_000), info.end_time);
+
+    // The last frame is cut short, it's not counted
+    fs::write(&x3a_path, &bytes[..bytes.len() - 10]).unwrap();
+    let info = x3a_info(&x3a_path).unwrap();
+    assert_eq!((2, 2000), (info.frames, info.samples));
+    assert_eq!(0.25, info.duration());
+    assert_eq!(Some(1_590_969_600_350_000), info.end_time);
+  }
+
+  #[test]
+  fn test_x3a_to_wav_gaps() {
+    let (x3a_path, wav) = write_archive_with_gap("to-wav-gap");
+
+    // Report, the samples are concatenated
+    let out_path = TempFile::new("report.wav");
+    let report = x3a_to_wav_with_options(&x3a_path, &out_path, &DecodeOptions::default()).unwrap();
+    assert_eq!(wav, read_wav(&out_path));
+    assert_eq!(vec![800], report.gaps.iter().map(|gap| gap.samples).collect::<Vec<_>>());
+
+    // Zero fill
+    let out_path = TempFile::new("zero-fill.wav");
+    let options = DecodeOptions {
+      gaps: GapMode::ZeroFill,
+      ..Default::default()
+    };
+    x3a_to_wav_with_options(&x3a_path, &out_path, &options).unwrap();
+    let mut expected = wav[..1000].to_vec();
+    expected.extend_from_slice(&[0; 800]);
+    expected.extend_from_slice(&wav[1000..]);
+    assert_eq!(expected, read_wav(&out_path));
+
+    // Split
+    let out_path = TempFile::new("split.wav");
+    let options = DecodeOptions {
+      gaps: GapMode::Split,
+      ..Default::default()
+    };
+    x3a_to_wav_with_options(&x3a_path, &out_path, &options).unwrap();
+    assert_eq!(&wav[..1000], &read_wav(&out_path)[..]);
+    assert_eq!(&wav[1000..], &read_wav(&TempFile::new("split_1.wav"))[..]);
+  }
+
+  #[test]
+  fn test_x3a_to_writer() {
+    let (x3a_path, wav) = write_archive_with_gap("to-writer");
+
+    // Raw samples, with the gap filled
+    let options = DecodeOptions {
+      gaps: GapMode::ZeroFill,
+      ..Default::default()
+    };
+    let mut raw = Vec::new();
+    x3a_to_writer(&x3a_path, &mut raw, StreamFormat::Raw, &options).unwrap();
+    let mut expected = wav[..1000].to_vec();
+    expected.extend_from_slice(&[0; 800]);
+    expected.extend_from_slice(&wav[1000..]);
+    let samples: Vec<i16> = raw.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+    assert_eq!(expected, samples);
+
+    // A wav stream doesn't know its length, the samples end at the end of the stream
+    let mut stream = Vec::new();
+    x3a_to_writer(&x3a_path, &mut stream, StreamFormat::Wav, &DecodeOptions::default()).unwrap();
+    let samples: Vec<i16> = hound::WavReader::new(&stream[..])
+      .unwrap()
+      .samples::<i16>()
+      .map_while(|s| s.ok())
+      .collect();
+    assert_eq!(wav, samples);
+  }
+
+  #[test]
+  fn test_frame_sample_count() {
+    let wav: Vec<i16> = (0..3000).map(|i| ((i * 37) % 101) as i16 - 50).collect();
+    let params = x3::Parameters::new(20, 50, [0, 1, 3], [3, 8, 20]).unwrap();
+    let mut x3a = encode_to_vec(&wav, 8000, params).unwrap();
+
+    // A block fewer samples in the header of the second frame, the payload CRC is intact
+    let frame_2 = frame_offsets(&x3a)[1];
+    let header = read_frame_header(&x3a[frame_2..]).unwrap();
+    let new_header = write_frame_header(
+      header.samples as usize - 20,
+      header.source_id,
+      header.channels,
+      header.payload_len,
+      header.payload_crc,
+      header.time,
+    );
+    x3a[frame_2..frame_2 + FrameHeader::LENGTH].copy_from_slice(&new_header);
+
+    assert!(matches!(decode_from_slice(&x3a), Err(X3Error::FrameDecodeSampleCount)));
+
+    let mut reader = X3aReader::new(&x3a[..]).unwrap();
+    let buf = &mut [0i16; 2000];
+    let mut decoded = Vec::new();
+    while let Some(samples) = reader.decode_next_frame(buf).unwrap() {
+      decoded.extend_from_slice(&buf[..samples]);
+    }
+    assert_eq!(&wav[..1000], &decoded[..1000]);
+    assert_eq!(&wav[2000..], &decoded[1000..]);
+    let report = reader.report();
+    assert_eq!(1, report.bad_frames.len());
+    assert!(matches!(report.bad_frames[0].error, X3Error::FrameDecodeSampleCount));
+    assert_eq!(FrameAction::Skipped, report.bad_frames[0].action);
+  }
+
+  #[test]
+  fn test_archive_header_xml_invalid() {
+    let wav: Vec<i16> = (0..1000).map(|i| ((i * 37) % 101) as i16 - 50).collect();
+    let x3a = encode_to_vec(&wav, 8000, x3::Parameters::default()).unwrap();
+
+    // The XML is not covered by a CRC, each field is replaced by one of the same length
+    for (field, damaged) in [
+      ("<FS UNIT=\"Hz\">8000</FS>", "<XS UNIT=\"Hz\">8000</XS>"),
+      ("<FS UNIT=\"Hz\">8000</FS>", "<FS UNIT=\"Hz\">80k0</FS>"),
+      ("<BLKLEN>20</BLKLEN>", "<BLKLEN>2x</BLKLEN>"),
+      ("<T N=\"3\">3,8,20</T>", "<T N=\"3\">3,8,2,</T>"),
+      ("<T N=\"3\">3,8,20</T>", "<T N=\"3\">3,8 20</T>"),
+      ("<CODES", "<XODES"),
+    ] {
+      let mut bytes = x3a.clone();
+      let p = bytes.windows(field.len()).position(|w| w == field.as_bytes()).unwrap();
+      bytes[p..p + field.len()].copy_from_slice(damaged.as_bytes());
+      if damaged == "<XODES" {
+        let p = bytes.windows(8).position(|w| w == b"</CODES>").unwrap();
+        bytes[p..p + 8].copy_from_slice(b"</XODES>");
+      }
+      assert!(
+        matches!(decode_from_slice(&bytes), Err(X3Error::ArchiveHeaderXMLInvalid)),
+        "{}",
+        damaged
+      );
+    }
+  }
 }
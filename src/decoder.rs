@@ -21,8 +21,10 @@
 
 use crate::bitreader::BitReader;
 use crate::crc;
+use crate::encoder;
 use crate::error;
-use crate::x3::{self, FrameHeader};
+use crate::lpc::Lpc;
+use crate::x3::{self, FrameHeader, Predictor, Sample, StereoMode};
 
 use byteorder::{BigEndian, ByteOrder};
 use error::X3Error;
@@ -33,28 +35,213 @@ pub enum FrameTest {
   NotFrame,
 }
 
-pub fn decode_frame(
-  x3_bytes: &mut [u8],
-  wav_buf: &mut [i16],
+///
+/// What `decode_frame` read from a frame payload, a frame header that doesn't agree with
+/// this is damaged.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodedFrame {
+  /// The total number of samples written to `wav_buf`.
+  pub samples: usize,
+
+  /// The number of payload bytes that were read, including the padding to a whole word.
+  pub payload_len: usize,
+}
+
+///
+/// Decode a frame payload.  The frame can contain several channels, these are written one
+/// channel after the other to `wav_buf`, each channel holding `samples` samples.  Use
+/// `interleave` to convert this to interleaved samples.
+///
+/// ### Arguments
+///
+/// * `x3_bytes` - the frame payload.
+/// * `wav_buf` - where the decoded samples will be written to.
+/// * `params` - the audio properties.
+/// * `samples` - the number of samples in each channel.
+/// * `channels` - the number of channels in the frame.
+///
+/// ### Returns
+///
+/// * the total number of samples written to `wav_buf`, and the length of the payload that
+///   was read.
+///
+pub fn decode_frame<S: Sample>(
+  x3_bytes: &[u8],
+  wav_buf: &mut [S],
   params: &x3::Parameters,
   samples: usize,
-) -> Result<Option<usize>, X3Error> {
-  let mut last_wav = BigEndian::read_i16(x3_bytes);
-  let mut p_wav = 0;
-  wav_buf[p_wav] = last_wav;
-  p_wav += 1;
-  let br = &mut BitReader::new(&x3_bytes[2..]);
-  let mut remaining_samples = samples - 1;
-
-  while remaining_samples > 0 {
-    let block_len = core::cmp::min(remaining_samples, params.block_len);
-    decode_block(br, &mut wav_buf[p_wav..(p_wav + block_len)], &mut last_wav, params)?;
-
-    remaining_samples -= block_len;
+  channels: usize,
+) -> Result<DecodedFrame, X3Error> {
+  decode_frame_blocks(x3_bytes, wav_buf, params, samples, channels, &mut 0)
+}
+
+///
+/// Decode a frame payload that may be damaged, such as one that failed its CRC or was cut
+/// short.  The blocks are decoded until one is invalid or runs past the end of `x3_bytes`,
+/// the samples from this block onwards are not written.
+///
+/// ### Arguments
+///
+/// * `x3_bytes` - the frame payload.
+/// * `wav_buf` - where the decoded samples will be written to, one channel after the other.
+/// * `params` - the audio properties.
+/// * `samples` - the number of samples in each channel.
+/// * `channels` - the number of channels in the frame.
+///
+/// ### Returns
+///
+/// * the number of samples of each channel that were decoded, and the error of the first
+///   invalid block.
+///
+pub fn decode_frame_partial<S: Sample>(
+  x3_bytes: &[u8],
+  wav_buf: &mut [S],
+  params: &x3::Parameters,
+  samples: usize,
+  channels: usize,
+) -> (usize, Option<X3Error>) {
+  let mut decoded = 0;
+  match decode_frame_blocks(x3_bytes, wav_buf, params, samples, channels, &mut decoded) {
+    Ok(_) => (samples, None),
+    Err(err) => (decoded, Some(err)),
+  }
+}
+
+//
+// Decode a frame payload, `decoded` is the number of samples of each channel that have been
+// decoded so far.
+//
+fn decode_frame_blocks<S: Sample>(
+  x3_bytes: &[u8],
+  wav_buf: &mut [S],
+  params: &x3::Parameters,
+  samples: usize,
+  channels: usize,
+  decoded: &mut usize,
+) -> Result<DecodedFrame, X3Error> {
+  if samples == 0 {
+    return Ok(DecodedFrame {
+      samples: 0,
+      payload_len: 0,
+    });
+  }
+  if wav_buf.len() < samples * channels {
+    return Err(X3Error::FrameDecodeBufferTooSmall);
+  }
+  let first_sample_bits = params.first_sample_bits();
+  if x3_bytes.len() * 8 < first_sample_bits * channels {
+    return Err(X3Error::FrameDecodeUnexpectedEnd);
+  }
+  let wav_buf = &mut wav_buf[..samples * channels];
+  let br = &mut BitReader::new(x3_bytes);
+
+  let mode = if params.decorrelate && channels == 2 {
+    StereoMode::from_bits(br.read_nbits(2))
+  } else {
+    StereoMode::Independent
+  };
+  let shift = if params.wasted_bits {
+    br.read_nbits(x3::Frame::SHIFT_LEN) as usize
+  } else {
+    0
+  };
+  if shift > params.nbits - x3::Parameters::MIN_NBITS {
+    return Err(X3Error::FrameDecodeInvalidShift);
+  }
+
+  // The first sample of each channel is stored as a raw value
+  for c in 0..channels {
+    wav_buf[c * samples] = S::from_i32(sign_extend(br.read_nbits(first_sample_bits), first_sample_bits));
+  }
+  if br.bits_read() > x3_bytes.len() * 8 {
+    return Err(X3Error::FrameDecodeUnexpectedEnd);
+  }
+  *decoded = 1;
+
+  // The LPC coefficients of each channel follow the first samples
+  let predictors = &mut [Predictor::Fixed(1); u8::MAX as usize];
+  for predictor in predictors[..channels].iter_mut() {
+    *predictor = match params.filter.fixed_predictor() {
+      Some(fixed) => fixed,
+      None => Predictor::Lpc(Lpc::read(br)?),
+    };
+  }
+
+  // The blocks of each channel are interleaved
+  let block_buf = &mut [[0i32; x3::Parameters::MAX_BLOCK_LENGTH]; 2];
+  let history_buf = &mut [[0i32; Predictor::MAX_ORDER]; 2];
+  let nbits = params.nbits - shift;
+  let mut p_wav = 1;
+  while p_wav < samples {
+    let block_len = core::cmp::min(samples - p_wav, params.block_len);
+    let [history_a, history_b] = history_buf;
+
+    if mode == StereoMode::Independent {
+      let block = &mut block_buf[0][..block_len];
+      for (ch_wav, predictor) in wav_buf.chunks_exact_mut(samples).zip(predictors.iter()) {
+        // The previous samples, these are needed for the prediction
+        let history = core::cmp::min(predictor.order(), p_wav);
+        for (h, w) in history_a.iter_mut().zip(&ch_wav[p_wav - history..p_wav]) {
+          *h = w.to_i32() >> shift;
+        }
+        let history = &history_a[..history];
+        decode_block(br, block, history, predictor, params, nbits)?;
+        for (w, b) in ch_wav[p_wav..p_wav + block_len].iter_mut().zip(block.iter()) {
+          *w = S::from_i32(*b << shift);
+        }
+      }
+    } else {
+      let (left, right) = wav_buf.split_at_mut(samples);
+      let order = core::cmp::max(predictors[0].order(), predictors[1].order());
+      let history = core::cmp::min(order, p_wav);
+      for i in 0..history {
+        let k = p_wav - history + i;
+        (history_a[i], history_b[i]) = mode.forward(left[k].to_i32() >> shift, right[k].to_i32() >> shift);
+      }
+      let (history_a, history_b) = (&history_a[..history], &history_b[..history]);
+      let [block_a, block_b] = block_buf;
+      let (block_a, block_b) = (&mut block_a[..block_len], &mut block_b[..block_len]);
+      decode_block(br, block_a, history_a, &predictors[0], params, mode.nbits(0, nbits))?;
+      decode_block(br, block_b, history_b, &predictors[1], params, mode.nbits(1, nbits))?;
+      for i in 0..block_len {
+        let (l, r) = mode.inverse(block_a[i], block_b[i]);
+        left[p_wav + i] = S::from_i32(l << shift);
+        right[p_wav + i] = S::from_i32(r << shift);
+      }
+    }
+
+    // A block that ran past the end of the payload read zeros, it's not complete
+    if br.bits_read() > x3_bytes.len() * 8 {
+      return Err(X3Error::FrameDecodeUnexpectedEnd);
+    }
     p_wav += block_len;
+    *decoded = p_wav;
   }
 
-  Ok(Some(p_wav))
+  Ok(DecodedFrame {
+    samples: samples * channels,
+    payload_len: br.bits_read().div_ceil(16) * 2,
+  })
+}
+
+///
+/// Convert samples stored one channel after the other (as written by `decode_frame`) to
+/// interleaved samples, as used by wav files.
+///
+/// ### Arguments
+///
+/// * `planar` - the samples of each channel, one channel after the other.
+/// * `interleaved` - where the interleaved samples are written to.
+/// * `channels` - the number of channels.
+///
+pub fn interleave<S: Sample, T: Sample>(planar: &[S], interleaved: &mut [T], channels: usize) {
+  let samples = planar.len() / channels;
+  for (c, ch_wav) in planar.chunks_exact(samples).enumerate() {
+    for (i, w) in ch_wav.iter().enumerate() {
+      interleaved[i * channels + c] = T::from_i32(w.to_i32());
+    }
+  }
 }
 
 ///
@@ -89,9 +276,6 @@ pub fn read_frame_header(bytes: &[u8]) -> Result<FrameHeader, X3Error> {
 
   // <Num Channels>
   let channels = bytes[FrameHeader::P_CHANNELS];
-  if channels > 1 {
-    return Err(X3Error::MoreThanOneChannel);
-  }
 
   // <Num Samples>
   let samples = BigEndian::read_u16(&bytes[FrameHeader::P_SAMPLES..]);
@@ -103,7 +287,7 @@ pub fn read_frame_header(bytes: &[u8]) -> Result<FrameHeader, X3Error> {
   }
 
   // <Time>
-  // Skip time
+  let time = BigEndian::read_i64(&bytes[FrameHeader::P_TIME..]);
 
   // <Payload CRC>
   let payload_crc = BigEndian::read_u16(&bytes[FrameHeader::P_PAYLOAD_CRC..]);
@@ -114,6 +298,7 @@ pub fn read_frame_header(bytes: &[u8]) -> Result<FrameHeader, X3Error> {
     channels,
     payload_len,
     payload_crc,
+    time,
   })
 }
 
@@ -124,35 +309,64 @@ pub fn read_frame_header(bytes: &[u8]) -> Result<FrameHeader, X3Error> {
 /// ### Arguments
 ///
 /// * `br` - the data to decode as a BitReader.
-/// * `wav` - where the wav data will be written to.
-/// * `last_wav` - the last value of the previous block.
-/// * `block_len` - how many bytes the decoded block will be.
+/// * `wav` - where the wav data will be written to, the length is the block length.
+/// * `history` - the last values of the previous block, the most recent value is last.
+/// * `predictor` - the predictor of the channel.
 /// * `params` - the audio properties.
+/// * `nbits` - the number of bits per sample.
 ///
 pub fn decode_block(
   br: &mut BitReader,
-  wav: &mut [i16],
-  last_wav: &mut i16,
+  wav: &mut [i32],
+  history: &[i32],
+  predictor: &Predictor,
   params: &x3::Parameters,
+  nbits: usize,
 ) -> Result<(), X3Error> {
   let ftype = br.read_nbits(2) as usize;
-  match ftype {
-    0 => decode_bpf_block(br, wav, last_wav),
-    1 => decode_ricecode_block_r1(br, wav, last_wav, params, ftype),
-    2 | 3 => decode_ricecode_block_r2r3(br, wav, last_wav, params, ftype),
-    _ => Err(X3Error::FrameDecodeInvalidFType),
+  let is_residual = match ftype {
+    0 => decode_bpf_block(br, wav, nbits)?,
+    1..=3 => {
+      let code = params.rice_codes[ftype - 1];
+      if code.nsubs == 0 {
+        decode_ricecode_block_r0(br, wav, code)?
+      } else {
+        decode_ricecode_block(br, wav, code)?
+      }
+    }
+    _ => return Err(X3Error::FrameDecodeInvalidFType),
+  };
+  if is_residual {
+    unfilter(predictor, history, wav);
   }
+  Ok(())
 }
 
-fn decode_ricecode_block_r1(
-  br: &mut BitReader,
-  wav: &mut [i16],
-  last_wav: &mut i16,
-  params: &x3::Parameters,
-  ftype: usize,
-) -> Result<(), X3Error> {
-  let code = params.rice_codes[ftype - 1];
-  let mut lw = *last_wav;
+//
+// Add the prediction to the residual in `wav`, this is the inverse of `encoder::residual`.
+//
+#[inline(always)]
+fn unfilter(predictor: &Predictor, history: &[i32], wav: &mut [i32]) {
+  let order = predictor.order();
+  let prev = &mut [0i32; Predictor::MAX_ORDER];
+  let mut len = core::cmp::min(order, history.len());
+  prev[..len].copy_from_slice(&history[history.len() - len..]);
+  for w in wav.iter_mut() {
+    *w += predictor.predict(&prev[..len]);
+    if len < order {
+      len += 1;
+    } else {
+      prev.copy_within(1..order, 0);
+    }
+    prev[len - 1] = *w;
+  }
+}
+
+//
+// Decode a block with the Rice code without suffix bits (RICE0), this is the most common
+// code so it has it's own loop.
+//
+fn decode_ricecode_block_r0(br: &mut BitReader, wav: &mut [i32], code: &x3::RiceCode) -> Result<bool, X3Error> {
   for b in 0..wav.len() {
     let i = br.count_zero_bits();
     br.read_nbits(1); // skip the next bit
@@ -161,77 +375,75 @@ fn decode_ricecode_block_r1(
     if i >= code.inv_len {
       return Err(X3Error::OutOfBoundsInverse);
     }
-    lw += unsafe { code.inv.get_unchecked(i) };
     let wav_value = unsafe { wav.get_unchecked_mut(b) };
-    *wav_value = lw;
+    *wav_value = i32::from(unsafe { *code.inv.get_unchecked(i) });
   }
-  *last_wav = lw;
-  Ok(())
+  Ok(true)
 }
 
-fn decode_ricecode_block_r2r3(
-  br: &mut BitReader,
-  wav: &mut [i16],
-  last_wav: &mut i16,
-  params: &x3::Parameters,
-  ftype: usize,
-) -> Result<(), X3Error> {
-  let code = params.rice_codes[ftype - 1];
-  let nb = if ftype == 2 { 2 } else { 4 };
+//
+// Decode a block with a Rice code that has `code.nsubs` suffix bits.
+//
+fn decode_ricecode_block(br: &mut BitReader, wav: &mut [i32], code: &x3::RiceCode) -> Result<bool, X3Error> {
+  let nb = code.nsubs + 1;
   let level = 1 << code.nsubs;
-  let mut lw = *last_wav;
   for b in 0..wav.len() {
-    let n = br.count_zero_bits() as i16;
-    let r = br.read_nbits(nb) as i16;
+    let n = br.count_zero_bits() as i32;
+    let r = br.read_nbits(nb) as i32;
     let i = (r + level * (n - 1)) as usize;
     if i >= code.inv_len {
       return Err(X3Error::OutOfBoundsInverse);
     }
-    lw += unsafe { code.inv.get_unchecked(i) };
     let wav_value = unsafe { wav.get_unchecked_mut(b) };
-    *wav_value = lw;
+    *wav_value = i32::from(unsafe { *code.inv.get_unchecked(i) });
   }
-  *last_wav = lw;
-  Ok(())
+  Ok(true)
 }
 
-fn unsigned_to_i16(a: u16, num_bits: usize) -> i16 {
-  let mut a = i32::from(a);
+fn unsigned_to_i32(a: u32, num_bits: usize) -> i32 {
+  let mut a = i64::from(a);
   let neg_thresh = 1 << (num_bits - 1);
   let neg = 1 << num_bits;
   // Need to convert this to a signed integer
   if a > neg_thresh {
     a -= neg;
   }
-  a as i16
+  a as i32
 }
 
-fn decode_bpf_block(br: &mut BitReader, wav: &mut [i16], last_wav: &mut i16) -> Result<(), X3Error> {
-  // This is a BFP or pass-through block
-  let num_bits = (br.read_nbits(4) + 1) as usize; // Read the rest of the block header
+fn sign_extend(a: u32, num_bits: usize) -> i32 {
+  let shift = 32 - num_bits;
+  ((a << shift) as i32) >> shift
+}
 
-  if num_bits <= 5 {
-    // We can't have BPF with length 5 or less.
-    return Err(X3Error::FrameDecodeInvalidBPF);
-  }
+//
+// Decode a BFP block, the residual is written to `wav`.  A pass-through block writes the
+// samples, in this case `false` is returned.
+//
+fn decode_bpf_block(br: &mut BitReader, wav: &mut [i32], nbits: usize) -> Result<bool, X3Error> {
+  // This is a BFP or pass-through block
+  let hdr_len = encoder::bfp_header_len(nbits);
+  let hdr = br.read_nbits(hdr_len) as usize; // Read the rest of the block header
 
-  if num_bits == 16 {
+  if hdr == (1 << hdr_len) - 1 {
     // This is a pass-through block
     for wav_value in wav.iter_mut() {
-      *wav_value = br.read_nbits(16) as i16;
-    }
-  } else {
-    // Otherwise, this is a BFP-encoded block with E + 1 bits/word
-    let mut value = *last_wav;
-    for wav_value in wav.iter_mut() {
-      let diff = br.read_nbits(num_bits) as u16;
-      value += unsigned_to_i16(diff, num_bits);
-      *wav_value = value;
+      *wav_value = sign_extend(br.read_nbits(nbits), nbits);
     }
+    return Ok(false);
   }
-  *last_wav = wav[wav.len() - 1];
 
-  Ok(())
+  let num_bits = hdr + 1;
+  if num_bits <= 5 || num_bits >= nbits {
+    // We can't have BPF with length 5 or less.
+    return Err(X3Error::FrameDecodeInvalidBPF);
+  }
+
+  // Otherwise, this is a BFP-encoded block with E + 1 bits/word
+  for wav_value in wav.iter_mut() {
+    *wav_value = unsigned_to_i32(br.read_nbits(num_bits), num_bits);
+  }
+  Ok(true)
 }
 
 //
@@ -250,27 +462,29 @@ fn decode_bpf_block(br: &mut BitReader, wav: &mut [i16], last_wav: &mut i16) ->
 mod tests {
   use crate::bitreader::BitReader;
   use crate::byteorder::{BigEndian, ByteOrder};
-  use crate::decoder::decode_block;
-  use crate::x3;
+  use crate::bytewriter::SliceByteWriter;
+  use crate::decoder::{decode_block, decode_frame, interleave, read_frame_header};
+  use crate::encoder::{encode_frame, encoded_len};
+  use crate::x3::{self, Filter, FrameHeader, Predictor, StereoMode};
 
   #[test]
   fn test_decode_block_ftype_1() {
     let x3_inp: &mut [u8] = &mut [
       0x01, 0x10, 0x23, 0x18, 0x14, 0x90, 0x40, 0x82, 0x58, 0x41, 0x02, 0x0C, 0x4C,
     ];
-    let wav: &mut [i16] = &mut [0i16; 20];
+    let wav: &mut [i32] = &mut [0i32; 20];
     let expected_wavput = [
       -375, -372, -374, -374, -376, -376, -373, -374, -373, -372, -375, -372, -375, -374, -375, -375, -373, -376, -373,
     ];
 
-    let mut last_wav = -373;
+    let last_wav = -373;
     let mut br = BitReader::new(x3_inp);
     let params = &x3::Parameters::default();
 
     // Skip 6 bits
     br.read_nbits(6);
 
-    decode_block(&mut br, wav, &mut last_wav, params).unwrap();
+    decode_block(&mut br, wav, &[last_wav], &Predictor::Fixed(1), params, 16).unwrap();
 
     assert_eq!(expected_wavput, &mut wav[0..expected_wavput.len()]);
   }
@@ -284,16 +498,16 @@ mod tests {
       0xf4, 0x29, 0x46, 0xd9, 0x12, 0x43, 0x4b, 0x4f, 0xd6, 0xeb, 0x24, 0xa8, 0x48, 0xc6, 0x3d, 0x1a, 0xb8, 0x71, 0x72,
       0xb5, 0x68, 0xb4, 0x5b, 0xa1, 0x7c, 0xb2, 0x48, 0x5f, 0x67, 0xd9, 0x1b, 0x65, 0x0,
     ];
-    let wav: &mut [i16] = &mut [0i16; 20];
+    let wav: &mut [i32] = &mut [0i32; 20];
     let expected_wavput = [
       -3467, -3471, -3466, -3463, -3463, -3465, -3464, -3456, -3450, -3448, -3449, -3456, -3462, -3456, -3462, -3461,
       -3463, -3468, -3462,
     ];
 
-    let mut last_wav = BigEndian::read_i16(&x3_inp[0..2]);
-    let mut br = BitReader::new(&mut x3_inp[2..]);
+    let last_wav = i32::from(BigEndian::read_i16(&x3_inp[0..2]));
+    let mut br = BitReader::new(&x3_inp[2..]);
     let params = &x3::Parameters::default();
-    decode_block(&mut br, wav, &mut last_wav, params).unwrap();
+    decode_block(&mut br, wav, &[last_wav], &Predictor::Fixed(1), params, 16).unwrap();
 
     assert_eq!(expected_wavput, &mut wav[0..expected_wavput.len()]);
   }
@@ -301,16 +515,16 @@ mod tests {
   #[test]
   fn test_decode_block_ftype_3() {
     let x3_inp: &mut [u8] = &mut [242, 123, 202, 56, 106, 202, 124, 8, 122, 249, 136, 173, 202, 23, 80];
-    let wav: &mut [i16] = &mut [0i16; 20];
+    let wav: &mut [i32] = &mut [0i32; 20];
     let expected_wavput = [
       -3452, -3441, -3456, -3462, -3453, -3461, -3461, -3449, -3457, -3463, -3460, -3454, -3450, -3449, -3452, -3450,
       -3449, -3463, -3462,
     ];
 
-    let mut last_wav = BigEndian::read_i16(&x3_inp[0..2]);
-    let mut br = BitReader::new(&mut x3_inp[2..]);
+    let last_wav = i32::from(BigEndian::read_i16(&x3_inp[0..2]));
+    let mut br = BitReader::new(&x3_inp[2..]);
     let params = &x3::Parameters::default();
-    decode_block(&mut br, wav, &mut last_wav, params).unwrap();
+    decode_block(&mut br, wav, &[last_wav], &Predictor::Fixed(1), params, 16).unwrap();
 
     assert_eq!(expected_wavput, &mut wav[0..expected_wavput.len()]);
   }
@@ -321,16 +535,16 @@ mod tests {
       129, 171, 62, 250, 4, 71, 75, 230, 252, 150, 153, 97, 24, 220, 83, 53, 143, 92, 101, 211, 155, 34, 73, 241, 221,
       200, 202, 252, 149, 240, 72, 20, 156, 172, 146, 59, 245, 23, 131, 33, 100, 0,
     ];
-    let wav: &mut [i16] = &mut [0i16; 19];
+    let wav: &mut [i32] = &mut [0i32; 19];
     let expected_wavput = [
       -16767, 4562, -1601, 9638, 22598, 14100, -12957, -10471, 29926, -14190, 31863, 29234, -16603, 31762, 1319, 11044,
       -28931, 17888, -14247,
     ];
 
-    let mut last_wav = BigEndian::read_i16(&x3_inp[0..2]);
-    let mut br = BitReader::new(&mut x3_inp[2..]);
+    let last_wav = i32::from(BigEndian::read_i16(&x3_inp[0..2]));
+    let mut br = BitReader::new(&x3_inp[2..]);
     let params = &x3::Parameters::default();
-    decode_block(&mut br, wav, &mut last_wav, params).unwrap();
+    decode_block(&mut br, wav, &[last_wav], &Predictor::Fixed(1), params, 16).unwrap();
 
     assert_eq!(expected_wavput, &mut wav[0..expected_wavput.len()]);
   }
@@ -340,17 +554,363 @@ mod tests {
     let x3_inp: &mut [u8] = &mut [
       242, 73, 24, 151, 240, 252, 191, 163, 225, 164, 48, 158, 196, 188, 251, 246, 20, 31, 240, 96,
     ];
-    let wav: &mut [i16] = &mut [0i16; 19];
+    let wav: &mut [i32] = &mut [0i32; 19];
     let expected_wavput = [
       -3493, -3494, -3487, -3501, -3502, -3467, -3483, -3506, -3500, -3491, -3501, -3483, -3490, -3495, -3500, -3495,
       -3492, -3493, -3490,
     ];
 
-    let mut last_wav = BigEndian::read_i16(&x3_inp[0..2]);
-    let mut br = BitReader::new(&mut x3_inp[2..]);
+    let last_wav = i32::from(BigEndian::read_i16(&x3_inp[0..2]));
+    let mut br = BitReader::new(&x3_inp[2..]);
     let params = &x3::Parameters::default();
-    decode_block(&mut br, wav, &mut last_wav, params).unwrap();
+    decode_block(&mut br, wav, &[last_wav], &Predictor::Fixed(1), params, 16).unwrap();
 
     assert_eq!(expected_wavput, &mut wav[0..expected_wavput.len()]);
   }
+
+  #[test]
+  fn test_decode_frame_two_channels() {
+    // Channel 0 is a ramp, channel 1 has large steps to force BFP and pass-through blocks
+    let mut wav = [0i16; 90];
+    for i in 0..45 {
+      wav[i] = (i as i16) * 3 - 50;
+      wav[45 + i] = if i % 2 == 0 { 20000 - i as i16 } else { -3000 };
+    }
+
+    let x3_output: &mut [u8] = &mut [0u8; 512];
+    {
+      let writer = &mut SliceByteWriter::new(x3_output);
+      let params = &x3::Parameters::default();
+      let stats: &mut [usize; 6] = &mut [0; 6];
+      encode_frame(&wav, 2, writer, params, 0, stats).unwrap();
+    }
+
+    let header = read_frame_header(x3_output).unwrap();
+    assert_eq!(2, header.channels);
+    assert_eq!(45, header.samples);
+
+    let payload = &x3_output[FrameHeader::LENGTH..FrameHeader::LENGTH + header.payload_len];
+    let planar: &mut [i16] = &mut [0i16; 90];
+    let params = &x3::Parameters::default();
+    let decoded = decode_frame(payload, planar, params, 45, 2).unwrap();
+    assert_eq!(90, decoded.samples);
+    assert_eq!(header.payload_len, decoded.payload_len);
+    assert_eq!(&wav[..], &planar[..]);
+
+    let interleaved: &mut [i16] = &mut [0i16; 90];
+    interleave(planar, interleaved, 2);
+    for i in 0..45 {
+      assert_eq!(wav[i], interleaved[2 * i]);
+      assert_eq!(wav[45 + i], interleaved[2 * i + 1]);
+    }
+  }
+
+  #[test]
+  fn test_decode_frame_stereo_decorrelation() {
+    // Closely correlated channels, with a few full scale samples to force pass-through blocks
+    let mut wav = [0i16; 120];
+    for i in 0..60 {
+      let v = ((i * 37) % 200) as i16 - 100;
+      wav[i] = v * 50;
+      wav[60 + i] = v * 50 + (i % 3) as i16;
+    }
+    wav[30] = i16::MIN;
+    wav[90] = i16::MAX;
+
+    let params = x3::Parameters {
+      decorrelate: true,
+      ..Default::default()
+    };
+
+    let x3_output: &mut [u8] = &mut [0u8; 1024];
+    {
+      let writer = &mut SliceByteWriter::new(x3_output);
+      let stats: &mut [usize; 6] = &mut [0; 6];
+      encode_frame(&wav, 2, writer, &params, 0, stats).unwrap();
+    }
+
+    let header = read_frame_header(x3_output).unwrap();
+    let payload = &x3_output[FrameHeader::LENGTH..FrameHeader::LENGTH + header.payload_len];
+    assert_ne!(
+      StereoMode::Independent,
+      StereoMode::from_bits(u32::from(payload[0] >> 6))
+    );
+
+    let planar: &mut [i16] = &mut [0i16; 120];
+    decode_frame(payload, planar, &params, 60, 2).unwrap();
+    assert_eq!(&wav[..], &planar[..]);
+
+    // Check the inverse of every mode at the extremes
+    for mode in StereoMode::ALL {
+      for (l, r) in [(-32768, 32767), (32767, -32768), (-32768, -32767), (1, -2), (0, 0)] {
+        let (a, b) = mode.forward(l, r);
+        assert_eq!((l, r), mode.inverse(a, b));
+      }
+    }
+  }
+
+  #[test]
+  fn test_decode_frame_24_bit() {
+    // A quiet section (Rice / BFP blocks) followed by full scale noise (pass-through blocks)
+    let mut wav = [0i32; 240];
+    for i in 0..120 {
+      let v = ((i * 37) % 200) as i32 - 100;
+      wav[i] = v * 5000;
+      wav[120 + i] = v * 5000 + (i % 3) as i32;
+    }
+    for (i, w) in wav[80..120].iter_mut().enumerate() {
+      *w = if i % 2 == 0 { -(1 << 23) } else { (1 << 23) - 1 };
+    }
+
+    for decorrelate in [false, true] {
+      let params = x3::Parameters {
+        nbits: 24,
+        decorrelate,
+        ..Default::default()
+      };
+
+      let x3_output: &mut [u8] = &mut [0u8; 2048];
+      {
+        let writer = &mut SliceByteWriter::new(x3_output);
+        let stats: &mut [usize; 6] = &mut [0; 6];
+        encode_frame(&wav, 2, writer, &params, 0, stats).unwrap();
+      }
+
+      let header = read_frame_header(x3_output).unwrap();
+      let payload = &x3_output[FrameHeader::LENGTH..FrameHeader::LENGTH + header.payload_len];
+
+      let planar: &mut [i32] = &mut [0i32; 240];
+      let decoded = decode_frame(payload, planar, &params, 120, 2).unwrap();
+      assert_eq!(header.payload_len, decoded.payload_len);
+      assert_eq!(&wav[..], &planar[..]);
+    }
+  }
+
+  #[test]
+  fn test_decode_frame_narrow_samples() {
+    // Full scale noise only fits in pass-through blocks
+    for nbits in [8, 10, 12] {
+      let max = (1 << (nbits - 1)) - 1;
+      let mut wav = [0i16; 100];
+      for (i, w) in wav.iter_mut().enumerate() {
+        *w = if i % 2 == 0 { -max - 1 } else { max } as i16;
+      }
+
+      let mut payload_len = 0;
+      for params_nbits in [16, nbits] {
+        let params = x3::Parameters {
+          nbits: params_nbits,
+          ..Default::default()
+        };
+
+        let x3_output: &mut [u8] = &mut [0u8; 1024];
+        {
+          let writer = &mut SliceByteWriter::new(x3_output);
+          let stats: &mut [usize; 6] = &mut [0; 6];
+          encode_frame(&wav, 1, writer, &params, 0, stats).unwrap();
+        }
+
+        let header = read_frame_header(x3_output).unwrap();
+        let payload = &x3_output[FrameHeader::LENGTH..FrameHeader::LENGTH + header.payload_len];
+
+        let decoded: &mut [i16] = &mut [0i16; 100];
+        decode_frame(payload, decoded, &params, 100, 1).unwrap();
+        assert_eq!(&wav[..], &decoded[..]);
+
+        if params_nbits == nbits {
+          // The narrow pass-through blocks are smaller than 16-bit BFP blocks
+          assert!(header.payload_len < payload_len);
+        }
+        payload_len = header.payload_len;
+      }
+    }
+  }
+
+  #[test]
+  fn test_decode_frame_wasted_bits() {
+    // 12-bit samples, left justified in 16 bits
+    let mut wav = [0i16; 200];
+    for i in 0..100 {
+      let v = ((i * 37) % 200) as i16 - 100;
+      wav[i] = v * 16 * 16;
+      wav[100 + i] = (v * 16 + (i % 5) as i16) * 16;
+    }
+    wav[50] = i16::MIN;
+    wav[150] = 0x7ff0;
+
+    for decorrelate in [false, true] {
+      let mut payload_len = 0;
+      for wasted_bits in [false, true] {
+        let params = x3::Parameters {
+          decorrelate,
+          wasted_bits,
+          ..Default::default()
+        };
+
+        let x3_output: &mut [u8] = &mut [0u8; 1024];
+        {
+          let writer = &mut SliceByteWriter::new(x3_output);
+          let stats: &mut [usize; 6] = &mut [0; 6];
+          encode_frame(&wav, 2, writer, &params, 0, stats).unwrap();
+        }
+
+        let header = read_frame_header(x3_output).unwrap();
+        let payload = &x3_output[FrameHeader::LENGTH..FrameHeader::LENGTH + header.payload_len];
+
+        let planar: &mut [i16] = &mut [0i16; 200];
+        let decoded = decode_frame(payload, planar, &params, 100, 2).unwrap();
+        assert_eq!(header.payload_len, decoded.payload_len);
+        assert_eq!(&wav[..], &planar[..]);
+
+        if wasted_bits {
+          let br = &mut BitReader::new(payload);
+          if decorrelate {
+            br.read_nbits(2);
+          }
+          assert_eq!(4, br.read_nbits(x3::Frame::SHIFT_LEN));
+          assert!(header.payload_len < payload_len);
+        }
+        payload_len = header.payload_len;
+      }
+    }
+  }
+
+  #[test]
+  fn test_decode_frame_filters() {
+    // A slowly changing tone is predicted better by the higher orders
+    let mut wav = [0i16; 400];
+    for i in 0..200 {
+      let t = i as f64 * 0.05;
+      wav[i] = (8000.0 * t.sin()) as i16;
+      wav[200 + i] = (7000.0 * (t + 0.3).sin()) as i16;
+    }
+
+    for decorrelate in [false, true] {
+      let mut payload_lens = [0; 4];
+      let filters = [Filter::Diff, Filter::Diff2, Filter::Diff3, Filter::Lpc];
+      for (f, filter) in filters.into_iter().enumerate() {
+        let params = x3::Parameters {
+          filter,
+          decorrelate,
+          ..Default::default()
+        };
+
+        let x3_output: &mut [u8] = &mut [0u8; 2048];
+        {
+          let writer = &mut SliceByteWriter::new(x3_output);
+          let stats: &mut [usize; 6] = &mut [0; 6];
+          encode_frame(&wav, 2, writer, &params, 0, stats).unwrap();
+        }
+
+        let header = read_frame_header(x3_output).unwrap();
+        let payload = &x3_output[FrameHeader::LENGTH..FrameHeader::LENGTH + header.payload_len];
+
+        let planar: &mut [i16] = &mut [0i16; 400];
+        let decoded = decode_frame(payload, planar, &params, 200, 2).unwrap();
+        assert_eq!(header.payload_len, decoded.payload_len);
+        assert_eq!(&wav[..], &planar[..]);
+        payload_lens[f] = header.payload_len;
+      }
+      assert!(payload_lens[1] < payload_lens[0]);
+      assert!(payload_lens[2] < payload_lens[1]);
+      assert!(payload_lens[3] < payload_lens[0]);
+    }
+
+    // The prediction at the start of a frame uses a lower order
+    assert_eq!(5, Predictor::Fixed(3).predict(&[5]));
+    assert_eq!(7, Predictor::Fixed(3).predict(&[3, 5]));
+    assert_eq!(10, Predictor::Fixed(3).predict(&[1, 3, 6]));
+    assert_eq!(9, Predictor::Fixed(2).predict(&[1, 3, 6]));
+  }
+
+  #[test]
+  fn test_decode_frame_optimised_params() {
+    let mut wav = [0i16; 1000];
+    for (i, w) in wav.iter_mut().enumerate() {
+      *w = ((i * 7919) % 23) as i16 - 11 + if i % 100 < 10 { 2000 } else { 0 };
+    }
+
+    let default_params = x3::Parameters::default();
+    let params = x3::Parameters::optimise(&wav);
+    let len = encoded_len(&wav, &params).unwrap();
+    assert!(len < encoded_len(&wav, &default_params).unwrap());
+
+    // Every Rice code set can be decoded
+    for codes in [[0, 1, 2], [0, 1, 3], [0, 2, 3], [1, 2, 3]] {
+      let thresholds = x3::RiceCodes::get(codes).map(|rc| rc.max_value());
+      let params = x3::Parameters::new(20, 50, codes, thresholds).unwrap();
+      let x3_output: &mut [u8] = &mut [0u8; 4096];
+      {
+        let writer = &mut SliceByteWriter::new(x3_output);
+        let stats: &mut [usize; 6] = &mut [0; 6];
+        encode_frame(&wav, 1, writer, &params, 0, stats).unwrap();
+      }
+
+      let header = read_frame_header(x3_output).unwrap();
+      let payload = &x3_output[FrameHeader::LENGTH..FrameHeader::LENGTH + header.payload_len];
+
+      let decoded: &mut [i16] = &mut [0i16; 1000];
+      decode_frame(payload, decoded, &params, 1000, 1).unwrap();
+      assert_eq!(&wav[..], &decoded[..]);
+    }
+  }
+
+  #[test]
+  fn test_decode_frame_low_thresholds() {
+    // A quiet signal, the residuals are above the thresholds but BFP words are short
+    let mut wav = [0i16; 1000];
+    for (i, w) in wav.iter_mut().enumerate() {
+      *w = ((i * 7919) % 15) as i16 - 7;
+    }
+
+    let low_params = x3::Parameters::new(20, 50, [0, 1, 2], [3, 6, 10]).unwrap();
+    let optimised = x3::Parameters::optimise(&wav);
+    for params in [low_params, optimised] {
+      let x3_output: &mut [u8] = &mut [0u8; 4096];
+      {
+        let writer = &mut SliceByteWriter::new(x3_output);
+        let stats: &mut [usize; 6] = &mut [0; 6];
+        encode_frame(&wav, 1, writer, &params, 0, stats).unwrap();
+      }
+
+      let header = read_frame_header(x3_output).unwrap();
+      let payload = &x3_output[FrameHeader::LENGTH..FrameHeader::LENGTH + header.payload_len];
+      let decoded: &mut [i16] = &mut [0i16; 1000];
+      decode_frame(payload, decoded, &params, 1000, 1).unwrap();
+      assert_eq!(&wav[..], &decoded[..]);
+    }
+  }
+
+  #[test]
+  fn test_decode_frame_exact_cost() {
+    // A quiet signal with a few outliers, and some loud blocks
+    let mut wav = [0i16; 1000];
+    for (i, w) in wav.iter_mut().enumerate() {
+      *w = ((i * 7919) % 7) as i16 - 3 + if i % 37 == 0 { 40 } else { 0 };
+      if i > 800 {
+        *w = w.wrapping_mul(1000);
+      }
+    }
+
+    let default_params = x3::Parameters::default();
+    let params = x3::Parameters {
+      exact_cost: true,
+      ..x3::Parameters::default()
+    };
+    assert!(encoded_len(&wav, &params).unwrap() < encoded_len(&wav, &default_params).unwrap());
+
+    let x3_output: &mut [u8] = &mut [0u8; 4096];
+    {
+      let writer = &mut SliceByteWriter::new(x3_output);
+      let stats: &mut [usize; 6] = &mut [0; 6];
+      encode_frame(&wav, 1, writer, &params, 0, stats).unwrap();
+    }
+
+    // The default decoder parameters read the output
+    let header = read_frame_header(x3_output).unwrap();
+    let payload = &x3_output[FrameHeader::LENGTH..FrameHeader::LENGTH + header.payload_len];
+    let decoded: &mut [i16] = &mut [0i16; 1000];
+    decode_frame(payload, decoded, &default_params, 1000, 1).unwrap();
+    assert_eq!(&wav[..], &decoded[..]);
+  }
 }
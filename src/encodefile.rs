@@ -19,24 +19,77 @@
  *                                                                        *
  **************************************************************************/
 
+// core and alloc
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
 // std
-use std::format;
+#[cfg(feature = "std")]
 use std::fs::File;
-use std::io::BufWriter;
+#[cfg(feature = "std")]
+use std::io::{BufWriter, Read, Write};
+#[cfg(feature = "std")]
 use std::path;
+#[cfg(feature = "std")]
+use std::vec;
 
 // externs
+#[cfg(feature = "std")]
+use crate::chrono::{DateTime, Utc};
+#[cfg(feature = "std")]
 use crate::hound;
 
 // this crate
-use crate::bytewriter::{ByteWriter, SeekFrom, StreamByteWriter};
-use crate::crc::{crc16, update_crc16}; 
+use crate::bytewriter::{ByteWriter, SeekFrom, VecByteWriter};
+#[cfg(feature = "std")]
+use crate::bytewriter::{FrameByteWriter, StreamByteWriter, FRAME_BUFFER_LENGTH};
+use crate::crc::{crc16, update_crc16};
 use crate::encoder;
 use crate::error;
+#[cfg(feature = "std")]
+use crate::seekindex::{self, IndexLocation, SeekIndex};
 use crate::x3;
 
 use error::X3Error;
 
+#[cfg(feature = "std")]
+///
+/// Options for `wav_to_x3a_with_options`.
+///
+#[derive(Clone, Default)]
+pub struct EncodeOptions {
+  /// The encoder parameters, `nbits` is replaced by `EncodeOptions::nbits`.
+  pub params: x3::Parameters,
+
+  /// The number of bits used by the samples, up to the width of the wav samples.  A sample
+  /// that doesn't fit is an error.  `None` uses the width of the wav samples.
+  pub nbits: Option<usize>,
+
+  /// Choose the block length, Rice codes and thresholds with `Parameters::optimise_from`,
+  /// using the start of the first channel.
+  pub optimise: bool,
+
+  /// The time of the first sample.  Each frame header has the time of its first sample,
+  /// counted from the start time using the sample rate.
+  pub start_time: Option<DateTime<Utc>>,
+
+  /// Write a seek index to a sidecar file or the end of the archive.
+  pub index: Option<IndexLocation>,
+}
+
+#[cfg(feature = "std")]
+// The number of samples of the first channel used to optimise the parameters
+const OPTIMISE_SAMPLES: usize = 1 << 16;
+
+#[cfg(feature = "std")]
+// A wav stream that doesn't know its length declares about 2 GiB of data or more, such as
+// 0x7fffffff bytes or the most a u32 holds
+const STREAMED_WAV_LEN: u64 = 0x7fff_0000;
+
+#[cfg(feature = "std")]
 ///
 /// Convert a .wav file to an .x3a (X3 Archive) file.  
 ///
@@ -46,42 +99,319 @@ use error::X3Error;
 /// * `x3a_filename` - the output X3A file.  It will be overwritten.
 ///
 pub fn wav_to_x3a<P: AsRef<path::Path>>(wav_filename: P, x3a_filename: P) -> Result<(), X3Error> {
-  let mut reader = hound::WavReader::open(wav_filename).unwrap();
-
-  // Can only handle 16 bit data
-  assert_eq!(reader.spec().bits_per_sample, 16);
-
-  // FIXME: We want to be able to handle multiple channels
-  assert_eq!(reader.spec().channels, 1);
-
-  let params = x3::Parameters::default();
-  let sample_rate = reader.spec().sample_rate;
+  wav_to_x3a_with_options(wav_filename, x3a_filename, &EncodeOptions::default())
+}
 
-  let samples = reader.samples::<i16>().map(|x| x.unwrap());
-  let mut first_channel = x3::IterChannel::new(0, samples, sample_rate, params);
+#[cfg(feature = "std")]
+///
+/// Convert a .wav file to an .x3a (X3 Archive) file, with the given options.
+///
+/// ### Arguments
+///
+/// * `wav_filename` - the input wav file to read.
+/// * `x3a_filename` - the output X3A file.  It will be overwritten.
+/// * `options` - the encoder parameters and options.
+///
+pub fn wav_to_x3a_with_options<P: AsRef<path::Path>>(
+  wav_filename: P,
+  x3a_filename: P,
+  options: &EncodeOptions,
+) -> Result<(), X3Error> {
+  let reader = hound::WavReader::open(wav_filename)?;
 
   // Open output file
   // Note (MSH): BufWriter is not necessary but should improve performance as
   //       underlying BitPacker struct performs many single byte writes.
-  let mut x3_output_file = File::create(x3a_filename)?;
+  let x3_output_file = File::create(&x3a_filename)?;
   let mut x3_buffered_writer = BufWriter::new(x3_output_file);
   let mut x3_output_writer = StreamByteWriter::new(&mut x3_buffered_writer);
   // let mut x3_output_writer = StreamByteWriter::new(&mut x3_output_file); // if not using BufWriter
-  
+
+  encode_wav(reader, options, &mut x3_output_writer)?;
+  x3_buffered_writer.flush()?;
+
+  if let Some(location) = options.index {
+    seekindex::write_index(x3a_filename, location, SeekIndex::DEFAULT_INTERVAL)?;
+  }
+  Ok(())
+}
+
+#[cfg(feature = "std")]
+///
+/// Convert a wav stream to an x3a stream, such as stdin to stdout.  Neither needs to seek,
+/// each frame is held in memory until its header is written.
+///
+/// A wav stream may not know its length when the header is written, the samples end at the
+/// end of the stream.  The seek index needs the archive to be read back, so `options.index`
+/// is not used.
+///
+/// ### Arguments
+///
+/// * `wav_reader` - the wav stream to read.
+/// * `x3a_writer` - where the X3A archive is written.
+/// * `options` - the encoder parameters and options.
+///
+pub fn wav_to_x3a_stream<R: Read, W: Write>(
+  wav_reader: R,
+  x3a_writer: W,
+  options: &EncodeOptions,
+) -> Result<(), X3Error> {
+  let reader = hound::WavReader::new(wav_reader)?;
+  let mut frame_buffer = vec![0u8; FRAME_BUFFER_LENGTH];
+  let mut x3_output_writer = FrameByteWriter::new(x3a_writer, &mut frame_buffer);
+  encode_wav(reader, options, &mut x3_output_writer)
+}
+
+///
+/// Encode a single channel as an X3A archive in memory, with the archive header.
+///
+/// ### Arguments
+///
+/// * `wav` - the samples to encode.
+/// * `sample_rate` - the number of samples per second.
+/// * `params` - the encoder parameters, `nbits` is the number of bits used by the samples.
+///
+/// ### Returns
+///
+/// * the bytes of the archive.
+///
+pub fn encode_to_vec(wav: &[i16], sample_rate: u32, params: x3::Parameters) -> Result<Vec<u8>, X3Error> {
+  encode_channels_to_vec(wav, 1, sample_rate, params)
+}
+
+///
+/// Encode samples of several channels as an X3A archive in memory, with the archive header.
+///
+/// ### Arguments
+///
+/// * `wav` - the samples to encode, with the channels interleaved.
+/// * `num_channels` - the number of channels, between 1 and 255.
+/// * `sample_rate` - the number of samples per second.
+/// * `params` - the encoder parameters, `nbits` is the number of bits used by the samples.
+///
+/// ### Returns
+///
+/// * the bytes of the archive.
+///
+pub fn encode_channels_to_vec(
+  wav: &[i16],
+  num_channels: usize,
+  sample_rate: u32,
+  params: x3::Parameters,
+) -> Result<Vec<u8>, X3Error> {
+  if num_channels == 0 || num_channels > u8::MAX as usize {
+    return Err(X3Error::InvalidNumChannels);
+  }
+  if !wav.len().is_multiple_of(num_channels) {
+    return Err(X3Error::ChannelLengthMismatch);
+  }
+  let mut writer = VecByteWriter::new();
+  encode_samples(wav.iter().copied(), num_channels, sample_rate, 0, params, &mut writer)?;
+  Ok(writer.into_inner())
+}
+
+#[cfg(feature = "std")]
+//
+// Encode a wav file as an archive, the parameters are optimised first if the options ask.
+//
+fn encode_wav<R: Read, W: ByteWriter>(
+  reader: hound::WavReader<R>,
+  options: &EncodeOptions,
+  writer: &mut W,
+) -> Result<(), X3Error> {
+  // A wav file may have fewer valid bits than it stores, these are the high bits of each
+  // sample so the full width is encoded unless the options ask for fewer.
+  let spec = reader.spec();
+  let wav_nbits = spec.bits_per_sample.div_ceil(8) as usize * 8;
+  let params = x3::Parameters {
+    nbits: options.nbits.unwrap_or(wav_nbits),
+    ..options.params.clone()
+  };
+  params.check_nbits()?;
+  if params.nbits > wav_nbits {
+    return Err(X3Error::InvalidNBits);
+  }
+
+  let start_time = options.start_time.map_or(0, |t| t.timestamp_micros());
+  if wav_nbits <= 16 {
+    encode_wav_samples::<i16, _, _>(reader, params, options.optimise, start_time, writer)
+  } else {
+    encode_wav_samples::<i32, _, _>(reader, params, options.optimise, start_time, writer)
+  }
+}
+
+#[cfg(feature = "std")]
+//
+// Encode the samples of a wav file.  When optimising, the start of the file is held in
+// memory while the parameters are chosen, so the reader doesn't need to seek.
+//
+fn encode_wav_samples<S, R, W>(
+  reader: hound::WavReader<R>,
+  mut params: x3::Parameters,
+  optimise: bool,
+  start_time: i64,
+  writer: &mut W,
+) -> Result<(), X3Error>
+where
+  S: hound::Sample + x3::Sample,
+  R: Read,
+  W: ByteWriter,
+{
+  let spec = reader.spec();
+  let num_channels = spec.channels as usize;
+
+  // A streamed wav file that doesn't know its length has the longest length possible, the
+  // samples end at the end of the stream, after the last whole sample of every channel.  Any
+  // other read error ends the samples, and is returned once the archive is written.
+  let data_len = reader.len() as u64 * spec.bits_per_sample.div_ceil(8) as u64;
+  let streamed = data_len >= STREAMED_WAV_LEN;
+  let mut num_read = 0;
+  let mut read_error = None;
+  let mut samples = reader.into_samples::<S>().map_while(|s| match s {
+    Ok(s) => {
+      num_read += 1;
+      Some(s)
+    }
+    Err(err) => {
+      if !(streamed && is_end_of_stream(&err) && num_read % num_channels == 0) {
+        read_error = Some(err);
+      }
+      None
+    }
+  });
+
+  let mut start = Vec::new();
+  if optimise {
+    start.extend(samples.by_ref().take(OPTIMISE_SAMPLES * num_channels));
+    let first_channel = start.iter().step_by(num_channels).copied().collect::<Vec<S>>();
+    params = params.optimise_from(&first_channel);
+  }
+
+  let samples = start.into_iter().chain(samples);
+  encode_samples(samples, num_channels, spec.sample_rate, start_time, params, writer)?;
+
+  match read_error {
+    Some(err) => Err(err.into()),
+    None => Ok(()),
+  }
+}
+
+#[cfg(feature = "std")]
+//
+// Is the wav read error the end of the underlying stream.  hound reports a read that ran out
+// of bytes as an `Other` error.
+//
+fn is_end_of_stream(err: &hound::Error) -> bool {
+  use std::io::ErrorKind;
+  matches!(err, hound::Error::IoError(e) if matches!(e.kind(), ErrorKind::Other | ErrorKind::UnexpectedEof))
+}
+
+//
+// Encode the interleaved `samples` as an archive, header included.
+//
+fn encode_samples<S, I, W>(
+  samples: I,
+  num_channels: usize,
+  sample_rate: u32,
+  start_time: i64,
+  params: x3::Parameters,
+  writer: &mut W,
+) -> Result<(), X3Error>
+where
+  S: x3::Sample,
+  I: Iterator<Item = S>,
+  W: ByteWriter,
+{
+  let deinterleave = RefCell::new(Deinterleave::new(samples, num_channels));
+  let mut channels = (0..num_channels)
+    .map(|c| {
+      let wav = DeinterleavedChannel {
+        shared: &deinterleave,
+        channel: c,
+      };
+      let mut channel = x3::IterChannel::new(c as u16, wav, sample_rate, params.clone());
+      channel.start_time = start_time;
+      channel
+    })
+    .collect::<Vec<_>>();
+
   // Output file header
-  create_archive_header(&first_channel, &mut x3_output_writer)?;
+  create_archive_header(&channels[0], num_channels as u8, writer)?;
+  writer.frame_end()?;
 
-  encoder::encode(&mut [&mut first_channel], &mut x3_output_writer)?;
+  let mut channel_refs = channels.iter_mut().collect::<Vec<_>>();
+  encoder::encode(&mut channel_refs, writer)?;
 
   Ok(())
 }
 
+//
+// Splits interleaved samples into separate channels.  The encoder reads a whole frame from
+// one channel before moving to the next, so the samples of the other channels are queued
+// until they are read.
+//
+struct Deinterleave<I: Iterator> {
+  samples: I,
+  queues: Vec<VecDeque<I::Item>>,
+}
+
+impl<I> Deinterleave<I>
+where
+  I: Iterator,
+{
+  fn new(samples: I, num_channels: usize) -> Self {
+    Deinterleave {
+      samples,
+      queues: (0..num_channels).map(|_| VecDeque::new()).collect(),
+    }
+  }
+
+  fn next(&mut self, channel: usize) -> Option<I::Item> {
+    if let Some(sample) = self.queues[channel].pop_front() {
+      return Some(sample);
+    }
+
+    // Read the next sample for each channel
+    let mut result = None;
+    for c in 0..self.queues.len() {
+      let sample = self.samples.next()?;
+      if c == channel {
+        result = Some(sample);
+      } else {
+        self.queues[c].push_back(sample);
+      }
+    }
+    result
+  }
+}
+
+struct DeinterleavedChannel<'a, I: Iterator> {
+  shared: &'a RefCell<Deinterleave<I>>,
+  channel: usize,
+}
+
+impl<I> Iterator for DeinterleavedChannel<'_, I>
+where
+  I: Iterator,
+{
+  type Item = I::Item;
+
+  fn next(&mut self) -> Option<I::Item> {
+    self.shared.borrow_mut().next(self.channel)
+  }
+}
+
 //
 // Write <Archive Header> to the BitPacker output.
 //
-fn create_archive_header<I, W:ByteWriter>(ch: &x3::IterChannel<I>, writer: &mut W) -> Result<(), X3Error> 
-where 
-  I: Iterator<Item = i16>,
+fn create_archive_header<I, W: ByteWriter>(
+  ch: &x3::IterChannel<I>,
+  num_channels: u8,
+  writer: &mut W,
+) -> Result<(), X3Error>
+where
+  I: Iterator,
+  I::Item: x3::Sample,
 {
   // <Archive Id>
   writer.write_all(x3::Archive::ID)?;
@@ -104,8 +434,8 @@ where
       "<CODES N=\"4\">RICE{},RICE{},RICE{},BFP</CODES>",
       ch.params.codes[0], ch.params.codes[1], ch.params.codes[2]
     ),
-    "<FILTER>DIFF</FILTER>",
-    "<NBITS>16</NBITS>",
+    &format!("<FILTER>{}</FILTER>", filter_name(&ch.params)),
+    &format!("<NBITS>{}</NBITS>", ch.params.nbits),
     &format!(
       "<T N=\"3\">{},{},{}</T>",
       ch.params.thresholds[0], ch.params.thresholds[1], ch.params.thresholds[2]
@@ -131,18 +461,170 @@ where
   // Write the header details
   let return_position = writer.stream_position()?;
   writer.seek(SeekFrom::Start(frame_header_pos))?;
-  let frame_header = encoder::write_frame_header(0, 0, payload_len, payload_crc);
+  let frame_header = encoder::write_frame_header(0, 0, num_channels, payload_len, payload_crc, 0);
   writer.write_all(frame_header)?;
   writer.seek(SeekFrom::Start(return_position))?;
   Ok(())
 }
 
-// #[cfg(test)]
-// mod tests {
-//   use crate::encodefile::wav_to_x3a;
+//
+// The value of the <FILTER> element.  Options that other decoders don't know about are
+// appended, such that those decoders reject the file.
+//
+fn filter_name(params: &x3::Parameters) -> String {
+  let mut filter = String::from(params.filter.name());
+  if params.decorrelate {
+    filter.push_str(",STEREO");
+  }
+  if params.wasted_bits {
+    filter.push_str(",SHIFT");
+  }
+  filter
+}
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use crate::decodefile::decode_from_slice;
+  use crate::encodefile::{
+    encode_channels_to_vec, encode_to_vec, wav_to_x3a_stream, wav_to_x3a_with_options, EncodeOptions,
+  };
+  use crate::error::X3Error;
+  use crate::hound;
+  use crate::utils::TempFile;
+  use crate::x3;
+  use std::io::Cursor;
+  use std::vec::Vec;
+  use std::fs;
+
+  // use crate::encodefile::wav_to_x3a;
+
+  // #[test]
+  // fn test_encodefile() {
+  //   wav_to_x3a("~/../../../sounds/15s/NO96_15s.wav", "~/test.wav").unwrap();
+  // }
+
+  //
+  // A 16-bit wav file in memory, the samples are interleaved.
+  //
+  fn wav_bytes(wav: &[i16], channels: u16) -> Vec<u8> {
+    let spec = hound::WavSpec {
+      channels,
+      sample_rate: 8000,
+      bits_per_sample: 16,
+      sample_format: hound::SampleFormat::Int,
+    };
+    let mut bytes = Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+    for sample in wav {
+      writer.write_sample(*sample).unwrap();
+    }
+    writer.finalize().unwrap();
+    bytes.into_inner()
+  }
+
+  #[test]
+  fn test_encode_nbits() {
+    // 12-bit samples in a 16-bit wav file
+    let wav: Vec<i16> = (0..3000).map(|i| ((i * 1237) % 4096) as i16 - 2048).collect();
+    let encode = |wav: &[i16], nbits| {
+      let options = EncodeOptions {
+        nbits,
+        ..Default::default()
+      };
+      let mut x3a = Vec::new();
+      wav_to_x3a_stream(&wav_bytes(wav, 1)[..], &mut x3a, &options).map(|_| x3a)
+    };
+
+    let x3a_16 = encode(&wav, None).unwrap();
+    let x3a_12 = encode(&wav, Some(12)).unwrap();
+    assert!(x3a_12.len() < x3a_16.len());
+    let (spec, samples) = decode_from_slice(&x3a_12).unwrap();
+    assert_eq!(12, spec.params.nbits);
+    assert_eq!(wav, samples);
+
+    // A sample that doesn't fit, and more bits than the wav file has
+    let mut loud = wav.clone();
+    loud[1500] = 2048;
+    assert!(matches!(encode(&loud, Some(12)), Err(X3Error::SampleOutOfRange)));
+    assert!(matches!(encode(&wav, Some(20)), Err(X3Error::InvalidNBits)));
+  }
+
+  #[test]
+  fn test_encode_wav_read_error() {
+    let wav: Vec<i16> = (0..3000).map(|i| ((i * 37) % 200) as i16 - 100).collect();
+    let encode = |bytes: &[u8]| wav_to_x3a_stream(bytes, Vec::new(), &EncodeOptions::default());
+
+    // A wav file that was cut short
+    let bytes = wav_bytes(&wav, 2);
+    assert!(matches!(encode(&bytes[..bytes.len() - 100]), Err(X3Error::Hound(_))));
+
+    // A wav stream that doesn't know its length ends at the end of the stream, unless that
+    // is part way through a sample of each channel
+    let mut streamed = bytes.clone();
+    assert_eq!(b"data", &streamed[36..40]);
+    streamed[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+    streamed[40..44].copy_from_slice(&((u32::MAX - 36) / 4 * 4).to_le_bytes());
+    let mut x3a = Vec::new();
+    wav_to_x3a_stream(&streamed[..], &mut x3a, &EncodeOptions::default()).unwrap();
+    assert_eq!(wav, decode_from_slice(&x3a).unwrap().1);
+    let half_frame = &streamed[..streamed.len() - 2];
+    assert!(matches!(encode(half_frame), Err(X3Error::Hound(_))));
+  }
+
+  #[test]
+  fn test_wav_to_x3a_stream() {
+    // Encoding a stream writes the same archive as encoding a file
+    let wav: Vec<i16> = (0..5000).map(|i| ((i * 37) % 101) as i16 - 50).collect();
+    let bytes = wav_bytes(&wav, 1);
+    let (wav_path, x3a_path) = (TempFile::new("stream.wav"), TempFile::new("stream.x3a"));
+    fs::write(&wav_path, &bytes).unwrap();
+    wav_to_x3a_with_options(&wav_path, &x3a_path, &EncodeOptions::default()).unwrap();
+
+    let mut x3a = Vec::new();
+    wav_to_x3a_stream(&bytes[..], &mut x3a, &EncodeOptions::default()).unwrap();
+    assert_eq!(fs::read(&x3a_path).unwrap(), x3a);
+  }
+
+  #[test]
+  fn test_encode_to_vec_decode_from_slice() {
+    let wav: Vec<i16> = (0..5000).map(|i| ((i * 37) % 101) as i16 - 50).collect();
+    let params = x3::Parameters::new(20, 50, [0, 1, 3], [3, 8, 20]).unwrap();
+    let x3a = encode_to_vec(&wav, 8000, params).unwrap();
 
-//   #[test]
-//   fn test_encodefile() {
-//     wav_to_x3a("~/../../../sounds/15s/NO96_15s.wav", "~/test.wav").unwrap();
-//   }
-// }
+    let (spec, samples) = decode_from_slice(&x3a).unwrap();
+    assert_eq!(8000, spec.sample_rate);
+    assert_eq!(1, spec.channels);
+    assert_eq!(20, spec.params.block_len);
+    assert_eq!(wav, samples);
+
+    // The archive must be complete
+    assert!(decode_from_slice(&x3a[..x3a.len() - 1]).is_err());
+
+    // Stereo, the channels are interleaved
+    let stereo: Vec<i16> = wav.iter().flat_map(|&s| [s, -s / 2]).collect();
+    let x3a = encode_channels_to_vec(&stereo, 2, 8000, x3::Parameters::default()).unwrap();
+    let (spec, samples) = decode_from_slice(&x3a).unwrap();
+    assert_eq!(2, spec.channels);
+    assert_eq!(stereo, samples);
+    assert!(matches!(
+      encode_channels_to_vec(&stereo[1..], 2, 8000, x3::Parameters::default()),
+      Err(X3Error::ChannelLengthMismatch)
+    ));
+    assert!(matches!(
+      encode_channels_to_vec(&stereo, 0, 8000, x3::Parameters::default()),
+      Err(X3Error::InvalidNumChannels)
+    ));
+  }
+}
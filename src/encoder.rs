@@ -24,79 +24,85 @@ use crate::byteorder::{BigEndian, ByteOrder};
 
 // This crate
 use crate::bitpacker::BitPacker;
-use crate::bytewriter::{ByteWriter, SeekFrom};
+use crate::bytewriter::{ByteWriter, SeekFrom, SliceByteWriter};
 use crate::crc::crc16;
 use crate::error;
-use crate::x3;
+use crate::lpc::{self, Lpc};
+use crate::x3::{self, Filter, Predictor, Sample, StereoMode};
 
 use error::X3Error;
 
 #[cfg(feature = "std")]
-use std::println;
+use std::eprintln;
 
-#[cfg(feature = "alloc")]
-use alloc::vec::Vec;
-
-#[cfg(feature = "std")]
-use std::vec::Vec;
+#[cfg(any(feature = "alloc", feature = "std"))]
+use alloc::vec;
 
 ///
-/// Encode a wav file (represented as `Channels`).  The output will be written to `bp`.
+/// Encode a wav file (represented as `Channels`).  The output will be written to `writer`.
+///
+/// All channels are encoded into the same frames, so they must have the same number of
+/// samples.  The sample rate and parameters of the first channel are used for all channels.
+///
+/// `writer.frame_end` is called after each frame, the bytes before that are not written
+/// again.  A writer that can't seek, such as `FrameByteWriter`, stages the frame and writes it
+/// out then.  `writer` is not flushed.
 ///
 /// ### Arguments
 ///
-/// * `channels` - The list of channels to encode.  // FIXME: This is currently only one.
-/// * `bp` - A `BitPacker` where the compressed data will be written to.
+/// * `channels` - The list of channels to encode.
+/// * `writer` - A `ByteWriter` where the compressed data will be written to.
 ///
-pub fn encode<'a, I, W: ByteWriter>(channels: &mut [&mut x3::IterChannel<I>], writer: &mut W) -> Result<(), X3Error> 
-where 
-  I: Iterator<Item = i16>,
+pub fn encode<I, W: ByteWriter>(channels: &mut [&mut x3::IterChannel<I>], writer: &mut W) -> Result<(), X3Error>
+where
+  I: Iterator,
+  I::Item: Sample,
 {
-  if channels.len() > 1 {
-    return Err(X3Error::MoreThanOneChannel);
+  let num_channels = channels.len();
+  if num_channels == 0 || num_channels > u8::MAX as usize {
+    return Err(X3Error::InvalidNumChannels);
   }
-  let ch = &mut channels[0];
-  let wav = &mut ch.wav;
+  let params = &channels[0].params;
+  params.check_nbits()?;
 
-  let samples_per_frame = ch.params.block_len * ch.params.blocks_per_frame;
+  let samples_per_frame = params.block_len * blocks_per_frame(params, num_channels);
+  let nbits = params.nbits;
 
   let stats: &mut [usize; 6] = &mut [0; 6];
+  let mut samples_done = 0;
 
   #[cfg(any(feature = "alloc", feature = "std"))]
-  {
-    loop {
-      let frame_buffer = wav.by_ref().take(samples_per_frame).collect::<Vec<i16>>();
-      if frame_buffer.len() == 0 {
-        break;
-      }
-      encode_frame(&frame_buffer, writer, &ch.params, stats)?;
-    }
+  let frame_buffer = &mut vec![I::Item::default(); samples_per_frame * num_channels][..];
+
+  // FIXME: This could still be more memory efficient by collecting this iterator on the block level instead of the frame level.
+  // FIXME: This is the default frame size is used instead of maximum frame size
+  #[cfg(not(any(feature = "alloc", feature = "std")))]
+  let frame_buffer =
+    &mut [I::Item::default(); x3::Parameters::MAX_BLOCK_LENGTH * x3::Parameters::DEFAULT_BLOCKS_PER_FRAME];
+
+  // Every channel of a frame must fit in the fixed buffer
+  #[cfg(not(any(feature = "alloc", feature = "std")))]
+  if samples_per_frame * num_channels > frame_buffer.len() {
+    return Err(X3Error::InvalidNumChannels);
   }
-  #[cfg(not(feature = "std"))]
-  {
-    // FIXME: This could still be more memory efficient by collecting this iterator on the block level instead of the frame level.
-    // FIXME: This is the default frame size is used instead of maximum frame size
-    let mut frame_buffer = [0i16; x3::Parameters::MAX_BLOCK_LENGTH * x3::Parameters::DEFAULT_BLOCKS_PER_FRAME];
-    loop {
-      // collect frame samples
-      let mut frame_length = 0;
-      for (i, fs) in wav.by_ref().take(samples_per_frame).enumerate() {
-        frame_buffer[i] = fs;
-        frame_length = i;
-      }
 
-      if frame_length == 0 {
-        break;
-      }
-
-      encode_frame(&frame_buffer[..frame_length+1], writer, &ch.params, stats)?;
+  loop {
+    let frame_len = read_frame(channels, frame_buffer, samples_per_frame, nbits)?;
+    if frame_len == 0 {
+      break;
     }
+    let wav = &frame_buffer[..frame_len * num_channels];
+    let time = frame_time(channels[0].start_time, samples_done, channels[0].sample_rate);
+    encode_frame(wav, num_channels, writer, &channels[0].params, time, stats)?;
+    writer.frame_end()?;
+    samples_done += frame_len as u64;
   }
 
   #[cfg(feature = "std")]
   {
+    // The statistics go to stderr, the archive may be written to stdout
     let t = (stats[0] + stats[1] + stats[2] + stats[3] + stats[4] + stats[5]) as f32;
-    println!(
+    eprintln!(
       "\nStatistics:\n  Rice-0: {:.4}%\n  Rice-1: {:.4}%\n  Rice-2: {:.4}%\n  Rice-3: {:.4}%\n  BFP: {:.4}%\n  Pass-through {:.4}%\n",
       (stats[0] as f32 / t) * 100.0,
       (stats[1] as f32 / t) * 100.0,
@@ -110,17 +116,128 @@ where
   Ok(())
 }
 
+///
+/// The number of bytes needed to encode `samples`, a single channel, with `params`.  This
+/// is used to compare parameters, the samples are encoded frame by frame and discarded.
+///
+pub fn encoded_len<S: Sample>(samples: &[S], params: &x3::Parameters) -> Result<usize, X3Error> {
+  let samples_per_frame = params.block_len * blocks_per_frame(params, 1);
+  let buf = &mut [0u8; x3::FrameHeader::LENGTH + x3::Frame::MAX_LENGTH];
+  let stats: &mut [usize; 6] = &mut [0; 6];
+
+  let mut len = 0;
+  for wav in samples.chunks(samples_per_frame) {
+    let writer = &mut SliceByteWriter::new(buf);
+    encode_frame(wav, 1, writer, params, 0, stats)?;
+    len += writer.stream_position()? as usize;
+  }
+  Ok(len)
+}
+
+//
+// The time of the frame starting `samples` after `start_time`, in microseconds.
+//
+fn frame_time(start_time: i64, samples: u64, sample_rate: u32) -> i64 {
+  if sample_rate == 0 {
+    return start_time;
+  }
+  start_time + (samples * 1_000_000 / sample_rate as u64) as i64
+}
+
+//
+// The number of blocks of each channel in a frame.  The total number of samples in a frame
+// is kept the same as for a single channel, but it's reduced if the worst case payload
+// (every block passed through) would not fit in `Frame::MAX_LENGTH`.
+//
+fn blocks_per_frame(params: &x3::Parameters, channels: usize) -> usize {
+  let sample_bits = core::cmp::max(params.nbits + 1, 16);
+  let block_bits = params.block_len * sample_bits + BFP_FTYPE_LEN + bfp_header_len(params.nbits + 1);
+
+  // The stereo mode, the wasted bits shift, then the first sample and LPC coefficients of each channel
+  let mut header_bits = 2 + x3::Frame::SHIFT_LEN + channels * params.first_sample_bits();
+  if params.filter == Filter::Lpc {
+    header_bits += channels * Lpc::MAX_LEN;
+  }
+  let payload_bits = (x3::Frame::MAX_LENGTH - 2) * 8 - header_bits;
+  let max_blocks = core::cmp::min(
+    payload_bits / (block_bits * channels),
+    u16::MAX as usize / params.block_len,
+  );
+
+  core::cmp::max(1, core::cmp::min(params.blocks_per_frame / channels, max_blocks))
+}
+
+//
+// Collect the samples of the next frame from each channel.  The samples are stored one
+// channel after the other (planar) at the start of `frame_buffer`.
+//
+// ### Return
+// * the number of samples per channel, zero when all channels are exhausted.
+//
+fn read_frame<I>(
+  channels: &mut [&mut x3::IterChannel<I>],
+  frame_buffer: &mut [I::Item],
+  samples_per_frame: usize,
+  nbits: usize,
+) -> Result<usize, X3Error>
+where
+  I: Iterator,
+  I::Item: Sample,
+{
+  let max = (1 << (nbits - 1)) - 1;
+  let min = -max - 1;
+
+  let mut frame_len = 0;
+  for (c, ch) in channels.iter_mut().enumerate() {
+    let mut len = 0;
+    for fs in ch.wav.by_ref().take(samples_per_frame) {
+      let value = fs.to_i32();
+      if value < min || value > max {
+        return Err(X3Error::SampleOutOfRange);
+      }
+      frame_buffer[c * samples_per_frame + len] = fs;
+      len += 1;
+    }
+
+    if c == 0 {
+      frame_len = len;
+    } else if len != frame_len {
+      return Err(X3Error::ChannelLengthMismatch);
+    }
+  }
+
+  // The last frame may be short, so pack the channels together
+  if frame_len < samples_per_frame {
+    for c in 1..channels.len() {
+      let src = c * samples_per_frame;
+      frame_buffer.copy_within(src..src + frame_len, c * frame_len);
+    }
+  }
+
+  Ok(frame_len)
+}
+
 ///
 /// Write the frame header to the bit array.
 ///
 /// ### Arguments
 ///
-/// * `bp` - A `BitPacker` where the frame data will be written to.
-/// * `num_samples` - The number of samples that are contained in the wav.
+/// * `num_samples` - The number of samples in each channel of the frame.
 /// * `id` -  The source id.
+/// * `channels` - The number of channels in the frame.
+/// * `payload_len` - The number of compressed bytes in the payload.
+/// * `payload_crc` - The CRC of the payload.
+/// * `time` - The time of the first sample, microseconds since the Unix epoch.
 ///
-pub fn write_frame_header(num_samples: usize, id: u8, payload_len: usize, payload_crc: u16) -> [u8; x3::FrameHeader::LENGTH] {
-  let mut header =  [0u8; x3::FrameHeader::LENGTH];
+pub fn write_frame_header(
+  num_samples: usize,
+  id: u8,
+  channels: u8,
+  payload_len: usize,
+  payload_crc: u16,
+  time: i64,
+) -> [u8; x3::FrameHeader::LENGTH] {
+  let mut header = [0u8; x3::FrameHeader::LENGTH];
 
   // <Frame Key> = "x3"
   let mut p = 0;
@@ -132,10 +249,8 @@ pub fn write_frame_header(num_samples: usize, id: u8, payload_len: usize, payloa
   p += 1;
 
   // <Num Channels> = The number of channels
-  header[p] = id;
+  header[p] = channels;
   p += 1;
-  // FIXME: Should write the `channel.id` value
-  // BigEndian::write_u8(&mut header[p..], channel.id);
 
   // <Num Samples> = The number of uncompressed samples.
   BigEndian::write_u16(&mut header[p..], num_samples as u16);
@@ -146,7 +261,7 @@ pub fn write_frame_header(num_samples: usize, id: u8, payload_len: usize, payloa
   p += 2;
 
   // <Time> = The timestamp of the first sample in the frame.
-  // FIXME: Need to add the time
+  BigEndian::write_i64(&mut header[p..], time);
   p += 8;
 
   // <Header CRC> = CRC of the frame header
@@ -155,48 +270,107 @@ pub fn write_frame_header(num_samples: usize, id: u8, payload_len: usize, payloa
   p += 2;
 
   // <Payload CRC> = CRC of the payload
-  BigEndian::write_u16(&mut header[p..], payload_crc as u16);
+  BigEndian::write_u16(&mut header[p..], payload_crc);
 
   // Write it back to the bit stream
-  return header
+  header
 }
 
 ///
 /// Encode one frame and all it's blocks.  This takes the wav and converts it x3 compressed
 /// audio.
 ///
+/// The first sample of each channel is written first, then the blocks of each channel are
+/// interleaved, i.e. block 1 of every channel, then block 2 of every channel, and so on.
+/// When `params.decorrelate` is set, a frame with two channels starts with the 2 bit
+/// `StereoMode` that was used.  When `params.wasted_bits` is set, this is followed by the
+/// number of low bits that are zero in every sample, these are removed before the blocks
+/// are encoded.  The `Lpc` filter writes the coefficients of each channel after the first
+/// samples.
+///
 /// ### Arguments
-/// * `wav` - the raw audio data.
-/// * `last_wav` - the last wav value from the previous frame.
-/// * `bp` - Where the output x3 compressed bits will be written to.
+/// * `wav` - the raw audio data, one channel after the other.  Each sample is `params.nbits` wide.
+/// * `channels` - the number of channels in `wav`.
+/// * `writer` - Where the output x3 compressed bits will be written to.
 /// * `params` - The audio parameters.
+/// * `time` - The time of the first sample, microseconds since the Unix epoch.
 /// * `stats` - Used for statistics which get printed out at the end.
 ///
-pub fn encode_frame<W: ByteWriter>(
-  wav: &[i16],
+pub fn encode_frame<S: Sample, W: ByteWriter>(
+  wav: &[S],
+  channels: usize,
   writer: &mut W,
   params: &x3::Parameters,
+  time: i64,
   stats: &mut [usize; 6],
 ) -> Result<(), X3Error> {
+  let samples = wav.len() / channels;
+  let stereo = params.decorrelate && channels == 2;
+  let shift = if params.wasted_bits {
+    wasted_bits(wav, params.nbits)
+  } else {
+    0
+  };
+  let signal = FrameSignal {
+    wav,
+    samples,
+    shift,
+    mode: if stereo {
+      choose_stereo_mode(&wav[..samples], &wav[samples..])
+    } else {
+      StereoMode::Independent
+    },
+  };
+
   // Bookmark this location such that we can write the header here
   writer.align::<2>()?;
   let frame_header_pos = writer.stream_position()?;
   writer.seek(SeekFrom::Current(x3::FrameHeader::LENGTH as i64))?;
-  
+
   let (payload_len, payload_crc) = {
     let bp = &mut BitPacker::new(writer);
-    // Write first sample, <Audio State>, as a raw value
-    bp.write_bits(wav[0] as usize, 16)?;
+    if stereo {
+      bp.write_bits(signal.mode as usize, 2)?;
+    }
+    if params.wasted_bits {
+      bp.write_bits(shift, x3::Frame::SHIFT_LEN)?;
+    }
 
-    // This techincally has data shared across blocks, so use here instead
-    let mut wav_diff = diff(wav);
-    
-    let blocks = wav[1..].chunks(params.block_len);
-    for block in blocks {
+    // Write first sample of each channel, <Audio State>, as a raw value
+    for ch_wav in wav.chunks_exact(samples) {
+      bp.write_bits(ch_wav[0].to_i32() as usize, params.first_sample_bits())?;
+    }
+
+    // The LPC coefficients of each channel follow the first samples
+    let predictors = &mut [Predictor::Fixed(1); u8::MAX as usize];
+    for (c, predictor) in predictors[..channels].iter_mut().enumerate() {
+      *predictor = match params.filter.fixed_predictor() {
+        Some(fixed) => fixed,
+        None => {
+          let lpc = lpc::analyse(|i| signal.get(c, i), samples);
+          lpc.write(bp)?;
+          Predictor::Lpc(lpc)
+        }
+      };
+    }
+
+    let block = &mut [0i32; x3::Parameters::MAX_BLOCK_LENGTH + Predictor::MAX_ORDER];
+    for start in (1..samples).step_by(params.block_len) {
+      let end = core::cmp::min(start + params.block_len, samples);
 
       // pack the data block for each channel
-      let ftype = x3_encode_block(block, &mut wav_diff, bp, params)?;
-      stats[ftype] += block.len();
+      for (c, predictor) in predictors[..channels].iter().enumerate() {
+        // Include the last samples of the previous block, these are needed for the prediction
+        let history = core::cmp::min(predictor.order(), start);
+        let block = &mut block[..end - start + history];
+        for (i, b) in block.iter_mut().enumerate() {
+          *b = signal.get(c, start - history + i);
+        }
+        let nbits = signal.mode.nbits(c, params.nbits - shift);
+        let residual = &mut residual(block, history, predictor);
+        let ftype = x3_encode_block(&block[history..], residual, bp, params, nbits)?;
+        stats[ftype] += end - start;
+      }
     }
 
     // Wrap the bit to the next significant bit
@@ -207,21 +381,92 @@ pub fn encode_frame<W: ByteWriter>(
   // Write the header details
   let return_position = writer.stream_position()?;
   writer.seek(SeekFrom::Start(frame_header_pos))?;
-  let frame_header = write_frame_header(wav.len(), 1, payload_len, payload_crc);
+  let frame_header = write_frame_header(samples, 1, channels as u8, payload_len, payload_crc, time);
   writer.write_all(frame_header)?;
   writer.seek(SeekFrom::Start(return_position))?;
   Ok(())
 }
 
 //
-// Calcuate the diff (first order differential) of the raw audio data.
+// The number of low bits that are zero in every sample.  At least `MIN_NBITS` bits are
+// kept, so the blocks can still be encoded.
 //
-// ### Return
-// * the maximum absolute value found in the diff.
+fn wasted_bits<S: Sample>(wav: &[S], nbits: usize) -> usize {
+  let bits = wav.iter().fold(0, |acc, w| acc | w.to_i32());
+  if bits == 0 {
+    return 0;
+  }
+  core::cmp::min(bits.trailing_zeros() as usize, nbits - x3::Parameters::MIN_NBITS)
+}
+
+//
+// The samples of each channel in a frame, after the wasted bits are removed and the stereo
+// decorrelation has been applied.
+//
+struct FrameSignal<'a, S> {
+  wav: &'a [S],
+  samples: usize,
+  shift: usize,
+  mode: StereoMode,
+}
+
+impl<S: Sample> FrameSignal<'_, S> {
+  #[inline(always)]
+  fn get(&self, channel: usize, i: usize) -> i32 {
+    if self.mode == StereoMode::Independent {
+      return self.wav[channel * self.samples + i].to_i32() >> self.shift;
+    }
+    let left = self.wav[i].to_i32() >> self.shift;
+    let right = self.wav[self.samples + i].to_i32() >> self.shift;
+    let (a, b) = self.mode.forward(left, right);
+    if channel == 0 {
+      a
+    } else {
+      b
+    }
+  }
+}
+
+//
+// Choose the stereo mode with the smallest sum of absolute differences, this is a good
+// estimate of which mode will result in the least number of bits.
+//
+fn choose_stereo_mode<S: Sample>(left: &[S], right: &[S]) -> StereoMode {
+  let mut sum_left = 0u64;
+  let mut sum_right = 0u64;
+  let mut sum_mid = 0u64;
+  let mut sum_side = 0u64;
+  for i in 1..left.len() {
+    let (l0, l1) = (left[i - 1].to_i32(), left[i].to_i32());
+    let (r0, r1) = (right[i - 1].to_i32(), right[i].to_i32());
+    sum_left += (l1 - l0).unsigned_abs() as u64;
+    sum_right += (r1 - r0).unsigned_abs() as u64;
+    sum_mid += (((l1 + r1) >> 1) - ((l0 + r0) >> 1)).unsigned_abs() as u64;
+    sum_side += ((l1 - r1) - (l0 - r0)).unsigned_abs() as u64;
+  }
+
+  let costs = [
+    sum_left + sum_right,
+    sum_left + sum_side,
+    sum_side + sum_right,
+    sum_mid + sum_side,
+  ];
+  let mut best = 0;
+  for (mode, cost) in costs.iter().enumerate() {
+    if *cost < costs[best] {
+      best = mode;
+    }
+  }
+  StereoMode::ALL[best]
+}
+
+//
+// Calculate the prediction residual of the raw audio data.  The first `history` samples of
+// `inp` are from the previous block, they are only used for the prediction.
 //
 #[inline(always)]
-fn diff<'a>(inp: &'a [i16]) -> impl Iterator<Item = i32> + 'a {
-  inp.windows(2).map(|w| i32::from(w[1]) - i32::from(w[0])) // collect on block level
+fn residual<'a>(inp: &'a [i32], history: usize, predictor: &'a Predictor) -> impl Iterator<Item = i32> + 'a {
+  (history..inp.len()).map(move |i| inp[i] - predictor.predict(&inp[..i])) // collect on block level
 }
 
 /// Count the number of bits that it takes to represent a number.
@@ -230,24 +475,26 @@ fn count_bits(n: u32) -> u32 {
   32 - n.leading_zeros()
 }
 
+///
+/// The number of bits in the BFP header for samples that are `nbits` wide.  The largest
+/// header value marks a pass-through block, otherwise a BFP block has `value + 1` bits
+/// per word.  For 16-bit samples this is 4 bits.
+///
+#[inline(always)]
+pub fn bfp_header_len(nbits: usize) -> usize {
+  count_bits(nbits as u32 - 2) as usize
+}
+
 fn encode_rice_block<W: ByteWriter>(
   wav_diff: &[i32],
   bp: &mut BitPacker<W>,
   params: &x3::Parameters,
-  max_abs_inp_filtd: i32,
+  ftype: usize,
 ) -> Result<usize, X3Error> {
   // Use Rice encoding method
 
-  let mut ftype: usize = 0; // find which code to use
-
-  for t in &params.thresholds {
-    if max_abs_inp_filtd > *t as i32 {
-      ftype += 1;
-    }
-  }
-
   // 2 bit rice block header
-  bp.write_bits(ftype as usize + 1, 2)?;
+  bp.write_bits(ftype + 1, 2)?;
   let rc = params.rice_codes[ftype];
   let codes = rc.code;
   let num_bits = rc.num_bits;
@@ -266,31 +513,44 @@ fn encode_rice_block<W: ByteWriter>(
   Ok(rc.nsubs)
 }
 
-fn encode_bfp_block<W: ByteWriter>(wav_diff: &[i32], bp: &mut BitPacker<W>, num_bits: usize) -> Result<usize, X3Error> {
-  bp.write_bits(num_bits as usize, BFP_HDR_LEN)?;
+fn encode_bfp_block<W: ByteWriter>(
+  wav_diff: &[i32],
+  bp: &mut BitPacker<W>,
+  num_bits: usize,
+  hdr_len: usize,
+) -> Result<usize, X3Error> {
+  bp.write_bits(num_bits, BFP_FTYPE_LEN + hdr_len)?;
   // Reduce the number of bits only.
   for wd in wav_diff {
-    bp.write_bits(*wd as usize,num_bits as usize + 1)?;
+    bp.write_bits(*wd as usize, num_bits + 1)?;
   }
   Ok(4)
 }
 
-fn encode_literal<W: ByteWriter>(wav: &[i16], bp: &mut BitPacker<W>) -> Result<usize, X3Error> {
+fn encode_literal<W: ByteWriter>(
+  wav: &[i32],
+  bp: &mut BitPacker<W>,
+  nbits: usize,
+  hdr_len: usize,
+) -> Result<usize, X3Error> {
   // We write all the bytes out without any compression
-  bp.write_bits(15, BFP_HDR_LEN)?;
+  bp.write_bits((1 << hdr_len) - 1, BFP_FTYPE_LEN + hdr_len)?;
   for w in wav {
-    bp.write_bits(*w as usize, i16::BITS as usize)?;
+    bp.write_bits(*w as usize, nbits)?;
   }
   Ok(5)
 }
 
-/// This will encode NSAMPLES of data.
-const BFP_HDR_LEN: usize = 6;
+/// The BFP and pass-through blocks start with a 2 bit zero ftype.
+const BFP_FTYPE_LEN: usize = 2;
+
+/// This will encode NSAMPLES of data, each sample is `nbits` wide.
 fn x3_encode_block<W: ByteWriter>(
-  wav: &[i16],
+  wav: &[i32],
   wav_diff_iter: &mut impl Iterator<Item = i32>,
   bp: &mut BitPacker<W>,
   params: &x3::Parameters,
+  nbits: usize,
 ) -> Result<usize, X3Error> {
   //collect wav_diff
   let wav_diff: &mut [i32] = &mut [0i32; x3::Parameters::MAX_BLOCK_LENGTH];
@@ -301,17 +561,83 @@ fn x3_encode_block<W: ByteWriter>(
   }
   let wav_diff = &wav_diff[..wav.len()];
 
+  if params.exact_cost {
+    return x3_encode_block_exact(wav, wav_diff, bp, params, nbits, max_abs_inp_filtd);
+  }
+
   if max_abs_inp_filtd <= params.thresholds[2] as i32 {
-    // 2 bit rice block header
-    encode_rice_block(wav_diff, bp, params, max_abs_inp_filtd)
+    // find which code to use
+    let ftype = params.thresholds.iter().filter(|t| max_abs_inp_filtd > **t as i32).count();
+    encode_rice_block(wav_diff, bp, params, ftype)
   } else {
-    let num_bits = count_bits(max_abs_inp_filtd as u32) as usize; // number of bits
-    if num_bits >= 15 {
-      encode_literal(wav, bp)
+    // Thresholds below 15 would give words shorter than the decoder accepts
+    let hdr_len = bfp_header_len(nbits);
+    let num_bits = (count_bits(max_abs_inp_filtd as u32) as usize).max(BFP_MIN_WORD_LEN - 1); // number of bits
+    if num_bits + 1 >= nbits || num_bits >= (1 << hdr_len) - 1 {
+      encode_literal(wav, bp, nbits, hdr_len)
     } else {
-      encode_bfp_block(wav_diff, bp, num_bits)
+      encode_bfp_block(wav_diff, bp, num_bits, hdr_len)
+    }
+  }
+}
+
+/// The smallest word length of a BFP block, the decoder rejects anything shorter.
+const BFP_MIN_WORD_LEN: usize = 6;
+
+//
+// Encode the block with whichever of the Rice codes, BFP or pass-through needs the fewest
+// bits, rather than choosing from the thresholds.  Only the three Rice codes in
+// `params.rice_codes` can be signalled by a block header, a code is only a candidate when
+// every residual is within its table.
+//
+fn x3_encode_block_exact<W: ByteWriter>(
+  wav: &[i32],
+  wav_diff: &[i32],
+  bp: &mut BitPacker<W>,
+  params: &x3::Parameters,
+  nbits: usize,
+  max_abs_inp_filtd: i32,
+) -> Result<usize, X3Error> {
+  let hdr_len = bfp_header_len(nbits);
+
+  enum Coder {
+    Rice(usize),
+    Bfp,
+    Literal,
+  }
+
+  // Pass-through always works
+  let mut best_cost = BFP_FTYPE_LEN + hdr_len + wav.len() * nbits;
+  let mut best = Coder::Literal;
+
+  // BFP, the word length includes the sign bit
+  let num_bits = (count_bits(max_abs_inp_filtd as u32) as usize).max(BFP_MIN_WORD_LEN - 1);
+  let bfp_valid = num_bits + 1 < nbits && num_bits < (1 << hdr_len) - 1;
+  if bfp_valid {
+    let cost = BFP_FTYPE_LEN + hdr_len + wav_diff.len() * (num_bits + 1);
+    if cost <= best_cost {
+      best_cost = cost;
+      best = Coder::Bfp;
+    }
+  }
+
+  // Rice codes, prefer the lower codes on a tie
+  for (ftype, rc) in params.rice_codes.iter().enumerate().rev() {
+    if max_abs_inp_filtd as usize > rc.max_value() {
+      continue;
+    }
+    let cost = 2 + wav_diff.iter().map(|w| rc.num_bits[(*w + rc.offset as i32) as usize]).sum::<usize>();
+    if cost <= best_cost {
+      best_cost = cost;
+      best = Coder::Rice(ftype);
     }
   }
+
+  match best {
+    Coder::Rice(ftype) => encode_rice_block(wav_diff, bp, params, ftype),
+    Coder::Bfp => encode_bfp_block(wav_diff, bp, num_bits, hdr_len),
+    Coder::Literal => encode_literal(wav, bp, nbits, hdr_len),
+  }
 }
 
 //
@@ -330,9 +656,13 @@ fn x3_encode_block<W: ByteWriter>(
 mod tests {
 
   use crate::bitpacker::BitPacker;
-  use crate::bytewriter::{ByteWriter, SliceByteWriter};
-  use crate::encoder::{encode_frame, diff, x3_encode_block};
-  use crate::x3::Parameters;
+  use crate::byteorder::{BigEndian, ByteOrder};
+  use crate::bytewriter::{ByteSink, ByteWriter, FrameByteWriter, SeekFrom, SliceByteWriter, FRAME_BUFFER_LENGTH};
+  use crate::crc::crc16;
+  use crate::decoder::read_frame_header;
+  use crate::encoder::{encode, encode_frame, residual, x3_encode_block};
+  use crate::error::X3Error;
+  use crate::x3::{FrameHeader, IterChannel, Parameters, Predictor};
 
   extern crate std;
 
@@ -411,7 +741,7 @@ mod tests {
 
     let expected_x3_output: &[u8] = &[
       // Frame header
-      'x' as u8, '3' as u8, // "x3"
+      b'x', b'3', // "x3"
       1, 1, // Source Id, Num Channels
       wlh, wll, // Num samples
       2, 144, // Num encoded bytes
@@ -453,7 +783,7 @@ mod tests {
       let params = &Parameters::default();
       let stats: &mut [usize; 6] = &mut [0; 6];
 
-      encode_frame(wav, writer, params, stats).unwrap();
+      encode_frame(wav, 1, writer, params, 0, stats).unwrap();
       writer.stream_position().unwrap() as usize
     };//writer scope
     assert_eq!(expected_x3_output, &x3_output[..valid_len]);
@@ -483,20 +813,48 @@ mod tests {
       let writer = &mut SliceByteWriter::new(x3_output);
       let params = &Parameters::default();
       let stats: &mut [usize; 6] = &mut [0; 6];
-      encode_frame(wav, writer, params, stats).unwrap();
+      encode_frame(wav, 1, writer, params, 0, stats).unwrap();
       writer.stream_position().unwrap() as usize
     }; // end writer scope
 
     assert_eq!(expected_x3_output, &x3_output[..valid_len]);
   }
 
+  #[test]
+  fn test_encode_frame_two_channels() {
+    let wav: &[i16] = &[0i16; 40];
+
+    let x3_output: &mut [u8] = &mut [0u8; NUM_SAMPLES * 2];
+    let valid_len = {
+      let writer = &mut SliceByteWriter::new(x3_output);
+      let params = &Parameters::default();
+      let stats: &mut [usize; 6] = &mut [0; 6];
+      encode_frame(wav, 2, writer, params, 0, stats).unwrap();
+      writer.stream_position().unwrap() as usize
+    }; // end writer scope
+
+    let expected_payload: &[u8] = &[
+      0, 0, 0, 0, // First sample of each channel
+      0x7f, 0xff, 0xfb, 0xff, 0xff, 0xc0, // Block 1 of channel 0, then block 1 of channel 1
+    ];
+    let header = &x3_output[..FrameHeader::LENGTH];
+    assert_eq!(2, header[FrameHeader::P_CHANNELS]);
+    assert_eq!(20, BigEndian::read_u16(&header[FrameHeader::P_SAMPLES..]));
+    assert_eq!(
+      expected_payload.len(),
+      BigEndian::read_u16(&header[FrameHeader::P_PAYLOAD_SIZE..]) as usize
+    );
+    assert_eq!(crc16(expected_payload), BigEndian::read_u16(&header[FrameHeader::P_PAYLOAD_CRC..]));
+    assert_eq!(expected_payload, &x3_output[FrameHeader::LENGTH..valid_len]);
+  }
+
   #[test]
   fn test_x3_encode_block() {
-    let wav: &[i16] = &[
+    let wav: &[i32] = &[
       -3461, -3452, -3441, -3456, -3462, -3453, -3461, -3461, -3449, -3457, -3463, -3460, -3454, -3450, -3449, -3452,
       -3450, -3449, -3463, -3462, -3462,
     ];
-    let mut wav_diff = diff(wav);
+    let mut wav_diff = residual(wav, 1, &Predictor::Fixed(1));
 
     let x3_output: &mut [u8] = &mut [0u8; NUM_SAMPLES * 2 + 1];
     let valid_length = {
@@ -504,7 +862,7 @@ mod tests {
       let bp = &mut BitPacker::new(writer);
       let params = &Parameters::default();
       // Run the code
-      x3_encode_block(&wav[1..], &mut wav_diff, bp, params).unwrap();
+      x3_encode_block(&wav[1..], &mut wav_diff, bp, params, 16).unwrap();
       let _ = bp.word_align();
       bp.len()
     };
@@ -518,7 +876,7 @@ mod tests {
 
   #[test]
   fn test_x3_encode_block_ftype3() {
-    let wav: &[i16] = &[
+    let wav: &[i32] = &[
       -3554,
       -3559,
       -3566,
@@ -541,7 +899,7 @@ mod tests {
       -3584,
       -3584 + 11,
     ];
-    let mut wav_diff = diff(wav);
+    let mut wav_diff = residual(wav, 1, &Predictor::Fixed(1));
 
     let x3_output: &mut [u8] = &mut [0u8; NUM_SAMPLES * 2 + 1];
     let valid_length = {
@@ -551,7 +909,7 @@ mod tests {
     
       // Run the code
       let _ = bp.write_packed_zeros(1);
-      x3_encode_block(&wav[1..], &mut wav_diff, bp, params).unwrap();
+      x3_encode_block(&wav[1..], &mut wav_diff, bp, params, 16).unwrap();
       let _ = bp.word_align();
       bp.len()
     };
@@ -564,11 +922,11 @@ mod tests {
 
   #[test]
   fn test_x3_encode_block_bpf_eq16() {
-    let wav: &[i16] = &[
+    let wav: &[i32] = &[
       -32341, -16767, 4562, -1601, 9638, 22598, 14100, -12957, -10471, 29926, -14190, 31863, 29234, -16603, 31762,
       1319, 11044, -28931, 17888, -14247, -14247,
     ];
-    let mut wav_diff = diff(wav);
+    let mut wav_diff = residual(wav, 1, &Predictor::Fixed(1));
 
     let x3_output: &mut [u8] = &mut [0u8; NUM_SAMPLES * 2 + 1];
     let valid_length = {
@@ -577,7 +935,7 @@ mod tests {
       let params = &Parameters::default();
     
       // Run the code
-      x3_encode_block(&wav[1..], &mut wav_diff, bp, params).unwrap();
+      x3_encode_block(&wav[1..], &mut wav_diff, bp, params, 16).unwrap();
       let _ = bp.word_align();
       bp.len()
     };
@@ -593,11 +951,11 @@ mod tests {
 
   #[test]
   fn test_x3_encode_block_bpf_lt16() {
-    let wav: &[i16] = &[
+    let wav: &[i32] = &[
       -3511, -3493, -3494, -3487, -3501, -3502, -3467, -3483, -3506, -3500, -3491, -3501, -3483, -3490, -3495, -3500,
       -3495, -3492, -3493, -3490, -3490,
     ];
-    let mut wav_diff = diff(wav);
+    let mut wav_diff = residual(wav, 1, &Predictor::Fixed(1));
 
     let x3_output: &mut [u8] = &mut [0u8; NUM_SAMPLES * 2 + 1];
     let valid_length = {
@@ -606,7 +964,7 @@ mod tests {
       let params = &Parameters::default();
     
       // Run the code
-      x3_encode_block(&wav[1..], &mut wav_diff, bp, params).unwrap();
+      x3_encode_block(&wav[1..], &mut wav_diff, bp, params, 16).unwrap();
       let _ = bp.word_align();
       bp.len()
     };
@@ -619,6 +977,157 @@ mod tests {
     assert_eq!(expected_x3_output, &x3_output[0..valid_length],);
   }
 
+  #[test]
+  fn test_x3_encode_block_exact_cost() {
+    // A quiet block with one outlier
+    let wav: &[i32] = &[0, 1, 0, -1, 0, 1, 2, 1, 0, -1, 20, 0, 1, 0, -1, 0, 0, 1, 0, -1, 0];
+
+    let encode_block = |params: &Parameters| {
+      let x3_output: &mut [u8] = &mut [0u8; 128];
+      let writer = &mut SliceByteWriter::new(x3_output);
+      let bp = &mut BitPacker::new(writer);
+      let mut wav_diff = residual(wav, 1, &Predictor::Fixed(1));
+      let ftype = x3_encode_block(&wav[1..], &mut wav_diff, bp, params, 16).unwrap();
+      bp.word_align().unwrap();
+      (ftype, bp.len())
+    };
+
+    let (ftype, len) = encode_block(&Parameters::default());
+    assert_eq!(4, ftype);
+
+    let params = Parameters {
+      exact_cost: true,
+      ..Default::default()
+    };
+    let (exact_ftype, exact_len) = encode_block(&params);
+    assert_eq!(3, exact_ftype);
+    assert!(exact_len < len);
+  }
+
+  #[test]
+  fn test_encode_frame_time() {
+    let params = Parameters::new(20, 10, [0, 1, 3], [3, 8, 20]).unwrap();
+    let wav = [0i16; 500];
+    let mut channel = IterChannel::new(0, wav.iter().copied(), 8000, params);
+    channel.start_time = 1_590_969_600_000_000; // 2020-06-01T00:00:00Z
+
+    let x3_output: &mut [u8] = &mut [0u8; 1024];
+    let len = {
+      let writer = &mut SliceByteWriter::new(x3_output);
+      encode(&mut [&mut channel], writer).unwrap();
+      writer.stream_position().unwrap() as usize
+    };
+
+    // Each frame has 200 samples, 25 ms
+    let mut times = std::vec::Vec::new();
+    let mut p = 0;
+    while p < len {
+      let header = read_frame_header(&x3_output[p..]).unwrap();
+      times.push(header.time);
+      p += FrameHeader::LENGTH + header.payload_len;
+    }
+    assert_eq!(
+      std::vec![1_590_969_600_000_000, 1_590_969_600_025_000, 1_590_969_600_050_000],
+      times
+    );
+  }
+
+  // A sink that can't seek, it counts the flushes
+  struct VecSink {
+    bytes: std::vec::Vec<u8>,
+    flushes: usize,
+  }
+
+  impl ByteSink for VecSink {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), X3Error> {
+      self.bytes.extend_from_slice(bytes);
+      Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), X3Error> {
+      self.flushes += 1;
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_encode_frame_byte_writer() {
+    let params = Parameters::new(20, 10, [0, 1, 3], [3, 8, 20]).unwrap();
+    let wav: std::vec::Vec<i16> = (0..500).map(|i| ((i * 37) % 101) as i16 - 50).collect();
+
+    let x3_output: &mut [u8] = &mut [0u8; 2048];
+    let len = {
+      let mut channel = IterChannel::new(0, wav.iter().copied(), 8000, params.clone());
+      let writer = &mut SliceByteWriter::new(x3_output);
+      encode(&mut [&mut channel], writer).unwrap();
+      writer.stream_position().unwrap() as usize
+    };
+
+    // The frames are staged and written in order, one flush each
+    let frame_buffer = &mut [0u8; FRAME_BUFFER_LENGTH];
+    let sink = VecSink {
+      bytes: std::vec::Vec::new(),
+      flushes: 0,
+    };
+    let mut channel = IterChannel::new(0, wav.iter().copied(), 8000, params);
+    let mut writer = FrameByteWriter::new(sink, frame_buffer);
+    encode(&mut [&mut channel], &mut writer).unwrap();
+    assert!(matches!(
+      writer.seek(SeekFrom::Start(0)),
+      Err(X3Error::ByteWriterSeekFlushed)
+    ));
+    let sink = writer.into_inner();
+    assert_eq!(3, sink.flushes);
+    assert_eq!(&x3_output[..len], &sink.bytes[..]);
+
+    // Other writers are not flushed for each frame
+    let mut channel = IterChannel::new(0, wav.iter().copied(), 8000, Parameters::default());
+    let mut writer = FlushCounter(SliceByteWriter::new(x3_output), 0);
+    encode(&mut [&mut channel], &mut writer).unwrap();
+    assert_eq!(0, writer.1);
+  }
+
+  // A writer that counts the flushes
+  struct FlushCounter<'a>(SliceByteWriter<'a>, usize);
+
+  impl ByteWriter for FlushCounter<'_> {
+    fn align<const N: usize>(&mut self) -> Result<usize, X3Error> {
+      self.0.align::<N>()
+    }
+
+    fn write_all(&mut self, value: impl AsRef<[u8]>) -> Result<(), X3Error> {
+      self.0.write_all(value)
+    }
+
+    fn flush(&mut self) -> Result<(), X3Error> {
+      self.1 += 1;
+      self.0.flush()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, X3Error> {
+      self.0.seek(pos)
+    }
+
+    fn stream_position(&mut self) -> Result<u64, X3Error> {
+      self.0.stream_position()
+    }
+  }
+
+  #[test]
+  fn test_encode_sample_out_of_range() {
+    let params = Parameters {
+      nbits: 12,
+      ..Default::default()
+    };
+    let wav = [0i16, 2047, -2048, 2048];
+    let mut channel = IterChannel::new(0, wav.iter().copied(), 44100, params);
+
+    let x3_output: &mut [u8] = &mut [0u8; 1024];
+    let writer = &mut SliceByteWriter::new(x3_output);
+    let result = encode(&mut [&mut channel], writer);
+    assert!(matches!(result, Err(X3Error::SampleOutOfRange)));
+  }
+
   // #[test]
   // fn test_x3_encode_samples() {
   //   let wav: Vec<i16> = vec![0; 1000];
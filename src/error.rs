@@ -31,13 +31,19 @@ pub enum X3Error {
   BitPack(crate::bitpacker::BitPackError),
 
   // Custom X3 Errors
-  InvalidEncodingThresh, // Threshold must be less than or equal to code.offset
+  InvalidEncodingThresh, // Threshold must be less than or equal to code.max_value()
   OutOfBoundsInverse,    // The value is out-of-bounds for the .inv array.
-  MoreThanOneChannel,    // FIXME: We need to support more than one channel
+  InvalidNumChannels,    // There must be between 1 and 255 channels, that fit in a frame
+  ChannelLengthMismatch, // All channels must have the same number of samples
+  InvalidNBits,          // The number of bits per sample is not supported
+  SampleOutOfRange,      // A sample doesn't fit in the number of bits per sample
+  InvalidBlockLength,    // The block length must be between 1 and Parameters::MAX_BLOCK_LENGTH
+  InvalidRiceCode,       // The Rice codes must be between 0 and 3
 
   // X3 Archive Header errors
   ArchiveHeaderXMLInvalid,    // XML is poorly structured
   ArchiveHeaderXMLRiceCode,   // XML has invalid rice code
+  ArchiveHeaderXMLFilter,     // XML has a filter that is not supported
   ArchiveHeaderXMLInvalidKey, // Invalid archive key 'X3ARHIV'
 
   // Frame issues
@@ -48,6 +54,7 @@ pub enum X3Error {
   FrameHeaderInvalidPayloadLen, // The payload length reaches beyond the end of the available data
   FrameHeaderInvalidHeaderCRC,
   FrameHeaderInvalidPayloadCRC,
+  FrameHeaderChannelMismatch, // The number of channels differs from the rest of the archive
 
   // Decoding issues
   FrameDecodeInvalidBlockLength, // The block length is bad
@@ -57,8 +64,18 @@ pub enum X3Error {
   FrameDecodeInvalidRiceCode,    // The Rice codes are invalid
   FrameDecodeInvalidBPF,         // The BPF decoder blew up, an invalid value was reached.
   FrameDecodeUnexpectedEnd,      // The BitReader has less bytes than the size of the header, but still expects a frame.
+  FrameDecodeBufferTooSmall,     // The output buffer can't hold all the samples of the frame.
+  FrameDecodeInvalidShift,       // The wasted bits shift leaves too few bits per sample.
+  FrameDecodeInvalidLpc,         // The LPC order is out of range.
+  FrameDecodeSampleCount,        // The frame decoded to a different number of samples than its header says.
+
+  // Seeking issues
+  SeekOutOfRange, // The sample or time is past the end of the archive
+  IndexInvalid,   // The seek index is damaged, or doesn't match the archive
 
   ByteWriterInsufficientMemory,
+  ByteWriterSeekFlushed, // The bytes before the last flush have been written out, they can't be sought to
+  ByteWriterSeekBeforeStart, // The position is before the first byte
 }
 
 #[cfg(feature = "std")]
@@ -27,6 +27,7 @@ extern crate std;
 extern crate alloc;
 
 extern crate byteorder;
+extern crate chrono;
 extern crate hound;
 
 pub mod bitpacker;
@@ -37,9 +38,18 @@ pub mod crc;
 #[cfg(feature = "std")]
 pub mod decodefile;
 pub mod decoder;
-#[cfg(feature = "std")]
+#[cfg(any(feature = "alloc", feature = "std"))]
 pub mod encodefile;
 pub mod encoder;
 pub mod error;
+pub mod lpc;
+#[cfg(feature = "std")]
+pub mod seekindex;
 mod utils;
 pub mod x3;
+
+// The archive header is parsed with quick-xml, which needs `std`
+#[cfg(feature = "std")]
+pub use decodefile::decode_from_slice;
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub use encodefile::{encode_channels_to_vec, encode_to_vec};
@@ -0,0 +1,272 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+use crate::bitpacker::BitPacker;
+use crate::bitreader::BitReader;
+use crate::bytewriter::ByteWriter;
+use crate::error::X3Error;
+
+///
+/// The quantized linear prediction coefficients of one channel in a frame.  These are
+/// written to the frame payload when the `LPC` filter is used.  The prediction is
+/// `sum(coefs[j] * x[n - 1 - j]) >> shift`.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lpc {
+  pub order: u8,
+  pub shift: u8,
+  pub coefs: [i16; Lpc::MAX_ORDER],
+}
+
+impl Lpc {
+  pub const MAX_ORDER: usize = 12;
+  pub const ORDER_LEN: usize = 4;
+  pub const SHIFT_LEN: usize = 4;
+  pub const COEF_LEN: usize = 15;
+
+  /// The largest number of bits written by `write`.
+  pub const MAX_LEN: usize = Self::ORDER_LEN + Self::SHIFT_LEN + Self::MAX_ORDER * Self::COEF_LEN;
+
+  // Predictions are limited to this, such that bad coefficients can't overflow the residual
+  const MAX_PREDICTION: i64 = 1 << 25;
+
+  ///
+  /// Predict the next sample from the previous samples in `history`, the most recent
+  /// sample is last.  At the start of a frame there are fewer than `order` samples, then the
+  /// previous sample is used.
+  ///
+  #[inline(always)]
+  pub fn predict(&self, history: &[i32]) -> i32 {
+    let order = self.order as usize;
+    if history.len() < order {
+      return history.last().copied().unwrap_or(0);
+    }
+    let mut sum = 0i64;
+    for (c, h) in self.coefs[..order].iter().zip(history.iter().rev()) {
+      sum += i64::from(*c) * i64::from(*h);
+    }
+    (sum >> self.shift).clamp(-Self::MAX_PREDICTION, Self::MAX_PREDICTION) as i32
+  }
+
+  /// The number of bits written by `write`.
+  pub fn num_bits(&self) -> usize {
+    Self::ORDER_LEN + Self::SHIFT_LEN + self.order as usize * Self::COEF_LEN
+  }
+
+  pub fn write<W: ByteWriter>(&self, bp: &mut BitPacker<W>) -> Result<(), X3Error> {
+    bp.write_bits(self.order as usize, Self::ORDER_LEN)?;
+    bp.write_bits(self.shift as usize, Self::SHIFT_LEN)?;
+    for c in &self.coefs[..self.order as usize] {
+      bp.write_bits(*c as usize, Self::COEF_LEN)?;
+    }
+    Ok(())
+  }
+
+  pub fn read(br: &mut BitReader) -> Result<Self, X3Error> {
+    let order = br.read_nbits(Self::ORDER_LEN) as u8;
+    if order == 0 || order as usize > Self::MAX_ORDER {
+      return Err(X3Error::FrameDecodeInvalidLpc);
+    }
+    let shift = br.read_nbits(Self::SHIFT_LEN) as u8;
+    let mut coefs = [0i16; Self::MAX_ORDER];
+    for c in coefs[..order as usize].iter_mut() {
+      // Sign extend the coefficient
+      *c = ((br.read_nbits(Self::COEF_LEN) << 1) as i16) >> 1;
+    }
+    Ok(Lpc { order, shift, coefs })
+  }
+}
+
+///
+/// Find the coefficients with the smallest cost for a channel of a frame.  Each order up
+/// to `Lpc::MAX_ORDER` is tried, the cost is an estimate of the number of bits needed for
+/// the residual and the coefficients.
+///
+/// ### Arguments
+///
+/// * `signal` - returns sample `n` of the channel.
+/// * `samples` - the number of samples in the channel.
+///
+pub fn analyse(signal: impl Fn(usize) -> i32, samples: usize) -> Lpc {
+  // The first order difference is the fallback, e.g. for silence
+  let mut coefs = [0i16; Lpc::MAX_ORDER];
+  coefs[0] = 1;
+  let mut best = Lpc {
+    order: 1,
+    shift: 0,
+    coefs,
+  };
+  let mut best_cost = cost(&best, &signal, samples);
+
+  let r = autocorrelation(&signal, samples);
+  let mut a = [0f64; Lpc::MAX_ORDER];
+  let mut err = r[0];
+  for order in 1..=Lpc::MAX_ORDER {
+    // Levinson-Durbin recursion, `a` becomes the coefficients of `order`
+    if err <= 0.0 {
+      break;
+    }
+    let mut acc = r[order];
+    for j in 0..order - 1 {
+      acc -= a[j] * r[order - 1 - j];
+    }
+    let k = acc / err;
+    let prev = a;
+    a[order - 1] = k;
+    for j in 0..order - 1 {
+      a[j] = prev[j] - k * prev[order - 2 - j];
+    }
+    err *= 1.0 - k * k;
+
+    if let Some(lpc) = quantize(&a[..order]) {
+      let c = cost(&lpc, &signal, samples);
+      if c < best_cost {
+        best = lpc;
+        best_cost = c;
+      }
+    }
+  }
+
+  best
+}
+
+fn autocorrelation(signal: &impl Fn(usize) -> i32, samples: usize) -> [f64; Lpc::MAX_ORDER + 1] {
+  // A Welch window reduces the effect of the frame edges
+  let half = samples as f64 / 2.0;
+  let windowed = |n: usize| {
+    let x = (n as f64 - half) / half;
+    f64::from(signal(n)) * (1.0 - x * x)
+  };
+
+  let mut r = [0f64; Lpc::MAX_ORDER + 1];
+  for n in 0..samples {
+    let x = windowed(n);
+    for (k, rk) in r.iter_mut().enumerate().take(n + 1) {
+      *rk += x * windowed(n - k);
+    }
+  }
+  r
+}
+
+//
+// Quantize the coefficients to `Lpc::COEF_LEN` bits, with the largest shift that fits.
+//
+fn quantize(a: &[f64]) -> Option<Lpc> {
+  let limit = ((1 << (Lpc::COEF_LEN - 1)) - 1) as f64;
+  let max = a.iter().fold(0.0, |m: f64, c| m.max(if *c < 0.0 { -c } else { *c }));
+  if max.is_nan() || max >= limit {
+    return None;
+  }
+
+  let mut shift = 0;
+  while shift < (1 << Lpc::SHIFT_LEN) - 1 && max * f64::from(2 << shift) <= limit {
+    shift += 1;
+  }
+
+  let scale = f64::from(1 << shift);
+  let mut coefs = [0i16; Lpc::MAX_ORDER];
+  for (q, c) in coefs.iter_mut().zip(a) {
+    let v = c * scale;
+    *q = if v < 0.0 { (v - 0.5) as i16 } else { (v + 0.5) as i16 };
+  }
+  Some(Lpc {
+    order: a.len() as u8,
+    shift,
+    coefs,
+  })
+}
+
+//
+// An estimate of the number of bits to encode `signal` with `lpc`.
+//
+fn cost(lpc: &Lpc, signal: &impl Fn(usize) -> i32, samples: usize) -> usize {
+  let history = &mut [0i32; Lpc::MAX_ORDER];
+  let mut bits = lpc.num_bits();
+  for n in 1..samples {
+    let len = core::cmp::min(n, lpc.order as usize);
+    for (i, h) in history[..len].iter_mut().enumerate() {
+      *h = signal(n - len + i);
+    }
+    let residual = signal(n) - lpc.predict(&history[..len]);
+    bits += (33 - residual.unsigned_abs().leading_zeros()) as usize;
+  }
+  bits
+}
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(test)]
+mod tests {
+  use crate::bitpacker::BitPacker;
+  use crate::bitreader::BitReader;
+  use crate::bytewriter::SliceByteWriter;
+  use crate::lpc::{analyse, Lpc};
+
+  extern crate std;
+
+  #[test]
+  fn test_lpc_write_read() {
+    let lpc = Lpc {
+      order: 3,
+      shift: 13,
+      coefs: [16383, -16383, -1, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    };
+
+    let out: &mut [u8] = &mut [0u8; 16];
+    {
+      let writer = &mut SliceByteWriter::new(out);
+      let bp = &mut BitPacker::new(writer);
+      lpc.write(bp).unwrap();
+      bp.word_align().unwrap();
+    }
+
+    let br = &mut BitReader::new(out);
+    assert_eq!(lpc, Lpc::read(br).unwrap());
+  }
+
+  #[test]
+  fn test_lpc_analyse() {
+    // A sine is predicted by the second order
+    let wav = (0..500)
+      .map(|i| (10000.0 * (i as f64 * 0.1).sin()) as i32)
+      .collect::<std::vec::Vec<_>>();
+    let lpc = analyse(|i| wav[i], wav.len());
+    assert!(lpc.order >= 2);
+    for n in 20..wav.len() {
+      assert!((wav[n] - lpc.predict(&wav[..n])).abs() <= 4);
+    }
+
+    // Silence uses the first order difference
+    let lpc = analyse(|_| 0, 100);
+    assert_eq!(1, lpc.order);
+    assert_eq!(7, lpc.predict(&[1, 7]));
+  }
+}
@@ -0,0 +1,329 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+// std
+use std::fs::{self, File, OpenOptions};
+use std::io::{prelude::*, SeekFrom};
+use std::path;
+use std::vec::Vec;
+
+// externs
+use crate::byteorder::{BigEndian, ByteOrder};
+
+// this crate
+use crate::crc;
+use crate::decodefile::X3aReader;
+use crate::decoder;
+use crate::encoder;
+use crate::error::X3Error;
+use crate::x3::{self, FrameHeader};
+
+///
+/// A frame that can be sought to without reading the frames before it.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SeekPoint {
+  /// The number of the frame, counted from the start of the archive.
+  pub frame: u64,
+
+  /// The first sample of the frame, counted for each channel from the start of the archive.
+  pub sample: u64,
+
+  /// The time of the frame, microseconds since the Unix epoch.  Frames without a time are
+  /// timed from the start of the archive.
+  pub time: i64,
+
+  /// The position of the frame header in the archive.
+  pub offset: u64,
+}
+
+///
+/// Where `write_index` stores the index.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexLocation {
+  /// A separate file next to the archive, "rec.x3a" has the index "rec.x3i".
+  Sidecar,
+
+  /// Frames appended to the archive.
+  Embedded,
+}
+
+///
+/// A table of seek points, one every few frames of an archive.
+///
+/// The index is stored as frames with the source id `FrameHeader::INDEX_SOURCE_ID`, either
+/// after the last frame of the archive or in a sidecar `.x3i` file.  Each frame holds seek
+/// points of 32 bytes, the frame, sample, time and offset as big endian 64-bit integers.  The last
+/// frame is the 16 byte locator, the position of the first index frame and the length of
+/// the archive that is indexed.  An embedded index follows the audio, so decoders that don't
+/// know the source id have decoded every frame before they reach it.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SeekIndex {
+  /// The seek points, in the order of the frames.
+  pub points: Vec<SeekPoint>,
+
+  /// The length of the archive up to the end of the last audio frame.
+  pub data_len: u64,
+}
+
+impl SeekIndex {
+  /// The number of frames between seek points.
+  pub const DEFAULT_INTERVAL: usize = 16;
+
+  const POINT_LEN: usize = 32;
+  const LOCATOR_LEN: usize = 16;
+  const POINTS_PER_FRAME: usize = (x3::Frame::MAX_LENGTH - 1) / Self::POINT_LEN;
+
+  ///
+  /// The last seek point at or before sample `n`.
+  ///
+  pub fn before_sample(&self, n: u64) -> Option<&SeekPoint> {
+    let i = self.points.partition_point(|p| p.sample <= n);
+    self.points[..i].last()
+  }
+
+  ///
+  /// The last seek point at or before time `t`, in microseconds since the Unix epoch.
+  ///
+  pub fn before_time(&self, t: i64) -> Option<&SeekPoint> {
+    let i = self.points.partition_point(|p| p.time <= t);
+    self.points[..i].last()
+  }
+
+  ///
+  /// The index frames, these start at byte `offset` of the file they are written to.
+  ///
+  pub fn to_bytes(&self, offset: u64, channels: u8) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for points in self.points.chunks(Self::POINTS_PER_FRAME) {
+      let mut payload = std::vec![0u8; points.len() * Self::POINT_LEN];
+      for (p, buf) in points.iter().zip(payload.chunks_mut(Self::POINT_LEN)) {
+        BigEndian::write_u64(&mut buf[0..], p.frame);
+        BigEndian::write_u64(&mut buf[8..], p.sample);
+        BigEndian::write_i64(&mut buf[16..], p.time);
+        BigEndian::write_u64(&mut buf[24..], p.offset);
+      }
+      push_frame(&mut bytes, channels, &payload);
+    }
+
+    // <Locator>
+    let mut locator = [0u8; Self::LOCATOR_LEN];
+    BigEndian::write_u64(&mut locator[0..], offset);
+    BigEndian::write_u64(&mut locator[8..], self.data_len);
+    push_frame(&mut bytes, channels, &locator);
+
+    bytes
+  }
+
+  ///
+  /// Read the index from the end of `file`.  The locator is the last frame, it gives the
+  /// position of the first index frame.
+  ///
+  pub fn read<F: Read + Seek>(file: &mut F) -> Result<Self, X3Error> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let locator_len = (FrameHeader::LENGTH + Self::LOCATOR_LEN) as u64;
+    if file_len < locator_len {
+      return Err(X3Error::IndexInvalid);
+    }
+
+    let mut buf = [0u8; FrameHeader::LENGTH + Self::LOCATOR_LEN];
+    file.seek(SeekFrom::Start(file_len - locator_len))?;
+    file.read_exact(&mut buf)?;
+    let locator = read_frame(&buf)?;
+    if locator.len() != Self::LOCATOR_LEN {
+      return Err(X3Error::IndexInvalid);
+    }
+    let offset = BigEndian::read_u64(&locator[0..]);
+    let data_len = BigEndian::read_u64(&locator[8..]);
+    if offset > file_len - locator_len {
+      return Err(X3Error::IndexInvalid);
+    }
+
+    let mut bytes = std::vec![0u8; (file_len - locator_len - offset) as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+
+    let mut points = Vec::new();
+    let mut p = 0;
+    while p < bytes.len() {
+      let payload = read_frame(&bytes[p..])?;
+      if payload.len() % Self::POINT_LEN != 0 {
+        return Err(X3Error::IndexInvalid);
+      }
+      for buf in payload.chunks(Self::POINT_LEN) {
+        points.push(SeekPoint {
+          frame: BigEndian::read_u64(&buf[0..]),
+          sample: BigEndian::read_u64(&buf[8..]),
+          time: BigEndian::read_i64(&buf[16..]),
+          offset: BigEndian::read_u64(&buf[24..]),
+        });
+      }
+      p += FrameHeader::LENGTH + payload.len();
+    }
+
+    Ok(SeekIndex { points, data_len })
+  }
+}
+
+///
+/// The sidecar index file of `x3a_filename`, the extension is replaced with ".x3i".
+///
+pub fn sidecar_path<P: AsRef<path::Path>>(x3a_filename: P) -> path::PathBuf {
+  x3a_filename.as_ref().with_extension("x3i")
+}
+
+///
+/// Index an archive, with a seek point every `interval` frames.
+///
+/// ### Arguments
+///
+/// * `x3a_filename` - the archive to index.
+/// * `location` - write a sidecar file, or append the index to the archive.
+/// * `interval` - the number of frames between seek points.
+///
+pub fn write_index<P: AsRef<path::Path>>(
+  x3a_filename: P,
+  location: IndexLocation,
+  interval: usize,
+) -> Result<(), X3Error> {
+  let mut reader = X3aReader::open(&x3a_filename)?;
+  let index = reader.build_index(interval)?;
+  let channels = reader.spec().channels;
+
+  match location {
+    IndexLocation::Sidecar => fs::write(sidecar_path(&x3a_filename), index.to_bytes(0, channels))?,
+    IndexLocation::Embedded => {
+      let mut file = OpenOptions::new().append(true).open(&x3a_filename)?;
+      let offset = file.metadata()?.len();
+      file.write_all(&index.to_bytes(offset, channels))?;
+    }
+  }
+  Ok(())
+}
+
+///
+/// Read the index of an archive, the sidecar file is preferred.  The index must cover the
+/// `data_len` bytes of audio frames in the archive.  A sidecar that is damaged, or doesn't
+/// cover the archive, is passed over for the embedded index.
+///
+pub(crate) fn read_index<P: AsRef<path::Path>>(x3a_filename: P, archive: &mut File) -> Result<SeekIndex, X3Error> {
+  let embedded = SeekIndex::read(archive);
+  if let Ok(mut sidecar) = File::open(sidecar_path(&x3a_filename)) {
+    let data_len = match &embedded {
+      Ok(index) => index.data_len,
+      Err(_) => archive.metadata()?.len(),
+    };
+    let index = SeekIndex::read(&mut sidecar)
+      .ok()
+      .filter(|index| index.data_len == data_len);
+    if let Some(index) = index {
+      return Ok(index);
+    }
+  }
+  embedded
+}
+
+//
+// Append an index frame with `payload` to `bytes`.
+//
+fn push_frame(bytes: &mut Vec<u8>, channels: u8, payload: &[u8]) {
+  let header = encoder::write_frame_header(
+    0,
+    FrameHeader::INDEX_SOURCE_ID,
+    channels,
+    payload.len(),
+    crc::crc16(payload),
+    0,
+  );
+  bytes.extend_from_slice(&header);
+  bytes.extend_from_slice(payload);
+}
+
+//
+// The payload of the index frame at the start of `bytes`.
+//
+fn read_frame(bytes: &[u8]) -> Result<&[u8], X3Error> {
+  let header = decoder::read_frame_header(bytes).map_err(|_| X3Error::IndexInvalid)?;
+  let payload = bytes
+    .get(FrameHeader::LENGTH..FrameHeader::LENGTH + header.payload_len)
+    .ok_or(X3Error::IndexInvalid)?;
+  if header.source_id != FrameHeader::INDEX_SOURCE_ID || crc::crc16(payload) != header.payload_crc {
+    return Err(X3Error::IndexInvalid);
+  }
+  Ok(payload)
+}
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(test)]
+mod tests {
+  use crate::error::X3Error;
+  use crate::seekindex::{SeekIndex, SeekPoint};
+  use std::io::Cursor;
+  use std::vec::Vec;
+
+  #[test]
+  fn test_seek_index_write_read() {
+    let points = (0..3000u64)
+      .map(|i| SeekPoint {
+        frame: i * 16,
+        sample: i * 16_000,
+        time: 1_590_969_600_000_000 + i as i64 * 2_000_000,
+        offset: 1000 + i * 4000,
+      })
+      .collect::<Vec<_>>();
+    let index = SeekIndex {
+      points,
+      data_len: 12_001_000,
+    };
+
+    // The index follows the archive, it needs more than one frame
+    let mut bytes = std::vec![0u8; 1000];
+    bytes.extend_from_slice(&index.to_bytes(1000, 2));
+    assert_eq!(index, SeekIndex::read(&mut Cursor::new(&bytes)).unwrap());
+
+    assert_eq!(Some(0), index.before_sample(0).map(|p| p.sample));
+    assert_eq!(Some(16_000), index.before_sample(31_999).map(|p| p.sample));
+    assert_eq!(Some(32_000), index.before_sample(32_000).map(|p| p.sample));
+    assert_eq!(None, index.before_time(1_590_969_599_999_999));
+    assert_eq!(Some(5000), index.before_time(1_590_969_603_000_000).map(|p| p.offset));
+
+    // A damaged index is not used
+    let len = bytes.len();
+    bytes[len - 30] ^= 1;
+    assert!(matches!(
+      SeekIndex::read(&mut Cursor::new(&bytes)),
+      Err(X3Error::IndexInvalid)
+    ));
+  }
+}
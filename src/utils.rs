@@ -18,3 +18,30 @@ pub const fn _unlikely(b: bool) -> bool {
   }
   b
 }
+
+//
+// A file in the temp directory, which is removed when the test ends.
+//
+#[cfg(all(test, feature = "std"))]
+pub struct TempFile(pub std::path::PathBuf);
+
+#[cfg(all(test, feature = "std"))]
+impl TempFile {
+  pub fn new(name: &str) -> Self {
+    TempFile(std::env::temp_dir().join(std::format!("x3-{}-{}", std::process::id(), name)))
+  }
+}
+
+#[cfg(all(test, feature = "std"))]
+impl AsRef<std::path::Path> for TempFile {
+  fn as_ref(&self) -> &std::path::Path {
+    &self.0
+  }
+}
+
+#[cfg(all(test, feature = "std"))]
+impl Drop for TempFile {
+  fn drop(&mut self) {
+    std::fs::remove_file(&self.0).ok();
+  }
+}
@@ -19,7 +19,9 @@
  *                                                                        *
  **************************************************************************/
 
+use crate::encoder;
 use crate::error::X3Error;
+use crate::lpc::Lpc;
 
 pub struct Decoder<'a> {
   pub channels: &'a [Channel<'a>],
@@ -44,19 +46,55 @@ impl<'a> Channel<'a> {
   }
 }
 
+///
+/// A single audio sample.  Samples are converted to `i32` while they are encoded or decoded,
+/// so 16-bit samples can be stored as `i16` and 24-bit samples as `i32`.
+///
+pub trait Sample: Copy + Default {
+  fn to_i32(self) -> i32;
+  fn from_i32(value: i32) -> Self;
+}
+
+impl Sample for i16 {
+  #[inline(always)]
+  fn to_i32(self) -> i32 {
+    i32::from(self)
+  }
+
+  #[inline(always)]
+  fn from_i32(value: i32) -> Self {
+    value as i16
+  }
+}
+
+impl Sample for i32 {
+  #[inline(always)]
+  fn to_i32(self) -> i32 {
+    self
+  }
+
+  #[inline(always)]
+  fn from_i32(value: i32) -> Self {
+    value
+  }
+}
+
 pub struct IterChannel<I>
 where
-  I: Iterator<Item = i16>,
+  I: Iterator,
+  I::Item: Sample,
 {
   pub id: u16,            // The channel number
   pub wav: I,             // Raw sample iterator
   pub sample_rate: u32,   // The sample rate in Hz
   pub params: Parameters, // X3 encoding parameters
+  pub start_time: i64,    // The time of the first sample, microseconds since the Unix epoch
 }
 
 impl<I> IterChannel<I>
 where
-  I: Iterator<Item = i16>,
+  I: Iterator,
+  I::Item: Sample,
 {
   pub fn new(id: u16, wav: impl IntoIterator<IntoIter = I>, sample_rate: u32, params: Parameters) -> Self {
     IterChannel {
@@ -64,6 +102,7 @@ where
       wav: wav.into_iter(),
       sample_rate,
       params,
+      start_time: 0,
     }
   }
 }
@@ -78,22 +117,49 @@ pub struct X3aSpec {
   pub channels: u8,
 }
 
+#[derive(Clone)]
 pub struct Parameters {
   pub block_len: usize,
   pub blocks_per_frame: usize,
   pub codes: [usize; 3],
   pub thresholds: [usize; 3],
   pub rice_codes: [&'static RiceCode; 3],
+
+  /// The predictor applied before encoding, written to the `<FILTER>` element.
+  pub filter: Filter,
+
+  /// Apply stereo decorrelation to frames with two channels.  This is signalled by the
+  /// `STEREO` option of the `<FILTER>` element.
+  pub decorrelate: bool,
+
+  /// Remove the low bits that are zero in every sample of a frame (the "wasted bits"), the
+  /// shift is written to each frame.  This is signalled by the `SHIFT` option of the
+  /// `<FILTER>` element.
+  pub wasted_bits: bool,
+
+  /// Choose the coder of each block by counting the exact number of bits each Rice code, BFP
+  /// and pass-through would need, instead of comparing the largest residual to `thresholds`.
+  /// This is slower, but a single large residual no longer forces a quiet block into BFP.
+  /// The output is read by any decoder.
+  pub exact_cost: bool,
+
+  /// The number of bits per sample, written to the `<NBITS>` element.  Samples must be in the
+  /// range of a signed `nbits` integer, e.g. -2048..=2047 for a 12-bit ADC.
+  pub nbits: usize,
 }
 
 impl Parameters {
   pub const MAX_BLOCK_LENGTH: usize = 60;
+  #[deprecated(note = "the sample size is set per archive, use the `nbits` field")]
   pub const WAV_BIT_SIZE: usize = 16;
+  pub const MIN_NBITS: usize = 8;
+  pub const MAX_NBITS: usize = 24;
 
   pub const DEFAULT_BLOCK_LENGTH: usize = 20;
   pub const DEFAULT_RICE_CODES: [usize; 3] = [0, 1, 3];
   pub const DEFAULT_THRESHOLDS: [usize; 3] = [3, 8, 20];
   pub const DEFAULT_BLOCKS_PER_FRAME: usize = 500;
+  pub const DEFAULT_NBITS: usize = 16;
 
   pub fn new(
     block_len: usize,
@@ -101,12 +167,17 @@ impl Parameters {
     codes: [usize; 3],
     thresholds: [usize; 3],
   ) -> Result<Self, X3Error> {
+    if block_len == 0 || block_len > Self::MAX_BLOCK_LENGTH {
+      return Err(X3Error::InvalidBlockLength);
+    }
+    if codes.iter().any(|&code| code >= RiceCodes::COUNT) {
+      return Err(X3Error::InvalidRiceCode);
+    }
     let rice_codes = RiceCodes::get(codes);
 
-    // setup the codes
-    for k in 0..2 {
-      let rc = rice_codes[k];
-      if thresholds[k] > rc.offset {
+    // setup the codes, each threshold must be a value its code can encode
+    for k in 0..3 {
+      if thresholds[k] > rice_codes[k].max_value() {
         return Err(X3Error::InvalidEncodingThresh);
       }
     }
@@ -117,10 +188,117 @@ impl Parameters {
       codes,
       thresholds,
       rice_codes,
+      filter: Filter::Diff,
+      decorrelate: false,
+      wasted_bits: false,
+      exact_cost: false,
+      nbits: Self::DEFAULT_NBITS,
     })
   }
 }
 
+impl Parameters {
+  ///
+  /// Check `nbits` is supported.
+  ///
+  pub fn check_nbits(&self) -> Result<(), X3Error> {
+    if self.nbits < Self::MIN_NBITS || self.nbits > Self::MAX_NBITS {
+      return Err(X3Error::InvalidNBits);
+    }
+    Ok(())
+  }
+
+  ///
+  /// The number of bits used to store the first sample of each channel in a frame.  This
+  /// is 16 bits, unless the samples are wider.
+  ///
+  pub fn first_sample_bits(&self) -> usize {
+    self.nbits.max(16)
+  }
+}
+
+impl Parameters {
+  // The candidates tried by `optimise_from`
+  const OPTIMISE_BLOCK_LENGTHS: [usize; 8] = [10, 15, 20, 25, 30, 40, 50, 60];
+  const OPTIMISE_RICE_CODES: [[usize; 3]; 4] = [[0, 1, 2], [0, 1, 3], [0, 2, 3], [1, 2, 3]];
+
+  ///
+  /// Find the block length, Rice codes and thresholds that give the smallest output for
+  /// 16 bit `samples`, the other parameters are the defaults.  See `optimise_from`.
+  ///
+  /// ### Arguments
+  ///
+  /// * `samples` - the audio of one channel.
+  ///
+  pub fn optimise(samples: &[i16]) -> Parameters {
+    Parameters::default().optimise_from(samples)
+  }
+
+  ///
+  /// Find the block length, Rice codes and thresholds that give the smallest output for
+  /// `samples`, the other parameters are kept.  All block lengths and Rice code sets are
+  /// tried, then the thresholds of the best are tuned one at a time.  This is slow, so
+  /// `samples` should be a short but typical part of the audio, from one channel.
+  ///
+  /// ### Arguments
+  ///
+  /// * `samples` - the audio of one channel, each sample is `self.nbits` wide.
+  ///
+  pub fn optimise_from<S: Sample>(&self, samples: &[S]) -> Parameters {
+    let cost = |params: &Parameters| encoder::encoded_len(samples, params).unwrap_or(usize::MAX);
+    let mut best = self.clone();
+    let mut best_len = cost(&best);
+
+    for block_len in Self::OPTIMISE_BLOCK_LENGTHS {
+      for codes in Self::OPTIMISE_RICE_CODES {
+        let rice_codes = RiceCodes::get(codes);
+
+        // Start with the default thresholds, limited to what each code can encode
+        let mut thresholds = Self::DEFAULT_THRESHOLDS;
+        for k in 0..3 {
+          thresholds[k] = thresholds[k].min(rice_codes[k].max_value());
+          if k > 0 {
+            thresholds[k] = thresholds[k].max(thresholds[k - 1]);
+          }
+        }
+
+        let params = Parameters {
+          block_len,
+          codes,
+          thresholds,
+          rice_codes,
+          ..self.clone()
+        };
+        let len = cost(&params);
+        if len < best_len {
+          best = params;
+          best_len = len;
+        }
+      }
+    }
+
+    // Tune the thresholds, they must stay in order.  The exact cost coder doesn't use them.
+    for k in (0..3).rev().filter(|_| !self.exact_cost) {
+      let min = if k == 0 { 0 } else { best.thresholds[k - 1] };
+      let max = match k {
+        2 => best.rice_codes[2].max_value(),
+        _ => best.thresholds[k + 1].min(best.rice_codes[k].max_value()),
+      };
+      for t in min..=max {
+        let mut params = best.clone();
+        params.thresholds[k] = t;
+        let len = cost(&params);
+        if len < best_len {
+          best = params;
+          best_len = len;
+        }
+      }
+    }
+
+    best
+  }
+}
+
 impl Default for Parameters {
   fn default() -> Self {
     Parameters {
@@ -129,6 +307,168 @@ impl Default for Parameters {
       codes: Self::DEFAULT_RICE_CODES,
       thresholds: Self::DEFAULT_THRESHOLDS,
       rice_codes: RiceCodes::get(Self::DEFAULT_RICE_CODES),
+      filter: Filter::Diff,
+      decorrelate: false,
+      wasted_bits: false,
+      exact_cost: false,
+      nbits: Self::DEFAULT_NBITS,
+    }
+  }
+}
+
+///
+/// The predictor applied to the samples before the blocks are encoded, this is the first
+/// value of the `<FILTER>` element.  Higher orders suit tonal signals.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+  /// First order difference, `x[n-1]`.
+  Diff,
+  /// Second order prediction, `2x[n-1] - x[n-2]`.
+  Diff2,
+  /// Third order prediction, `3x[n-1] - 3x[n-2] + x[n-3]`.
+  Diff3,
+  /// Linear prediction, the coefficients of each channel are chosen for every frame and
+  /// written to the frame payload.  This is slower to encode.
+  Lpc,
+}
+
+impl Filter {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "DIFF" => Some(Filter::Diff),
+      "DIFF2" => Some(Filter::Diff2),
+      "DIFF3" => Some(Filter::Diff3),
+      "LPC" => Some(Filter::Lpc),
+      _ => None,
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Filter::Diff => "DIFF",
+      Filter::Diff2 => "DIFF2",
+      Filter::Diff3 => "DIFF3",
+      Filter::Lpc => "LPC",
+    }
+  }
+
+  /// The predictor of a fixed filter, `Lpc` has no fixed predictor.
+  pub fn fixed_predictor(self) -> Option<Predictor> {
+    match self {
+      Filter::Diff => Some(Predictor::Fixed(1)),
+      Filter::Diff2 => Some(Predictor::Fixed(2)),
+      Filter::Diff3 => Some(Predictor::Fixed(3)),
+      Filter::Lpc => None,
+    }
+  }
+}
+
+///
+/// The predictor of one channel in a frame.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Predictor {
+  /// A fixed polynomial predictor of order 1 to 3.
+  Fixed(u8),
+  /// Linear prediction with the coefficients written to the frame.
+  Lpc(Lpc),
+}
+
+impl Predictor {
+  /// The largest number of previous samples used by a predictor.
+  pub const MAX_ORDER: usize = Lpc::MAX_ORDER;
+
+  /// The number of previous samples used by the predictor.
+  #[inline(always)]
+  pub fn order(&self) -> usize {
+    match self {
+      Predictor::Fixed(order) => *order as usize,
+      Predictor::Lpc(lpc) => lpc.order as usize,
+    }
+  }
+
+  ///
+  /// Predict the next sample from the previous samples in `history`, the most recent
+  /// sample is last.  A fixed predictor uses a lower order when there is not enough
+  /// history, this is the case at the start of a frame.
+  ///
+  #[inline(always)]
+  pub fn predict(&self, history: &[i32]) -> i32 {
+    let order = match self {
+      Predictor::Fixed(order) => core::cmp::min(*order as usize, history.len()),
+      Predictor::Lpc(lpc) => return lpc.predict(history),
+    };
+    let h = &history[history.len() - order..];
+    match order {
+      0 => 0,
+      1 => h[0],
+      2 => 2 * h[1] - h[0],
+      _ => 3 * (h[2] - h[1]) + h[0],
+    }
+  }
+}
+
+///
+/// The stereo decorrelation applied to a frame with two channels.  When decorrelation is
+/// enabled the mode is written as 2 bits at the start of the frame payload.  The side
+/// channel is the difference between the left and right channels and needs one extra bit.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StereoMode {
+  /// The left and right channels are coded independently.
+  Independent = 0,
+  /// The left and side channels are coded.
+  LeftSide = 1,
+  /// The side and right channels are coded.
+  RightSide = 2,
+  /// The mid and side channels are coded.
+  MidSide = 3,
+}
+
+impl StereoMode {
+  pub const ALL: [StereoMode; 4] = [
+    StereoMode::Independent,
+    StereoMode::LeftSide,
+    StereoMode::RightSide,
+    StereoMode::MidSide,
+  ];
+
+  pub fn from_bits(bits: u32) -> Self {
+    Self::ALL[(bits & 3) as usize]
+  }
+
+  /// Convert left and right samples to the coded channels.
+  #[inline(always)]
+  pub fn forward(self, left: i32, right: i32) -> (i32, i32) {
+    match self {
+      StereoMode::Independent => (left, right),
+      StereoMode::LeftSide => (left, left - right),
+      StereoMode::RightSide => (left - right, right),
+      StereoMode::MidSide => ((left + right) >> 1, left - right),
+    }
+  }
+
+  /// Convert the coded channels back to left and right samples.
+  #[inline(always)]
+  pub fn inverse(self, a: i32, b: i32) -> (i32, i32) {
+    match self {
+      StereoMode::Independent => (a, b),
+      StereoMode::LeftSide => (a, a - b),
+      StereoMode::RightSide => (a + b, b),
+      StereoMode::MidSide => {
+        let mid = (a << 1) | (b & 1);
+        ((mid + b) >> 1, (mid - b) >> 1)
+      }
+    }
+  }
+
+  /// The number of bits needed for the coded `channel`, given `nbits` bits per sample.
+  #[inline(always)]
+  pub fn nbits(self, channel: usize, nbits: usize) -> usize {
+    match (self, channel) {
+      (StereoMode::LeftSide, 1) | (StereoMode::RightSide, 0) | (StereoMode::MidSide, 1) => nbits + 1,
+      _ => nbits,
     }
   }
 }
@@ -143,6 +483,9 @@ impl Archive {
 pub struct Frame {}
 impl Frame {
   pub const MAX_LENGTH: usize = 0x7fe0;
+
+  /// The number of bits of the wasted bits shift, when `Parameters::wasted_bits` is set.
+  pub const SHIFT_LEN: usize = 5;
 }
 
 pub struct FrameHeader {
@@ -160,6 +503,9 @@ pub struct FrameHeader {
 
   /// The CRC16 value for the payload
   pub payload_crc: u16,
+
+  /// The time of the first sample in the frame, microseconds since the Unix epoch (UTC)
+  pub time: i64,
 }
 
 impl FrameHeader {
@@ -170,6 +516,9 @@ impl FrameHeader {
   pub const KEY: u16 = 30771; // "x3"
   pub const KEY_BUF: &'static [u8] = &[0x78, 0x33]; // "x3"
 
+  /// The source id of the frames of a seek index, these don't hold audio
+  pub const INDEX_SOURCE_ID: u8 = 0xff;
+
   /// The location of various bytes in the header
   pub const P_KEY: usize = 0;
   pub const P_SOURCE_ID: usize = 2;
@@ -193,6 +542,13 @@ pub struct RiceCode {
   pub inv_len: usize, // The length of inv that is used for this.
 }
 
+impl RiceCode {
+  /// The largest absolute value that can be encoded.
+  pub fn max_value(&self) -> usize {
+    core::cmp::min(self.offset, self.code.len() - 1 - self.offset)
+  }
+}
+
 pub struct RiceCodes {}
 
 // The inverse rice code lookup table is the same for all rice codes, although the lower valued
@@ -251,6 +607,9 @@ impl RiceCodes {
     },
   ];
 
+  /// The number of Rice codes.
+  pub const COUNT: usize = 4;
+
   pub fn get(code_list: [usize; 3]) -> [&'static RiceCode; 3] {
     [
       &RiceCodes::CODE[code_list[0]],